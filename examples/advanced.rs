@@ -0,0 +1,79 @@
+//! Combines several of the retry middleware options in one client.
+//!
+//! A local `wiremock` server answers the first request with `503 Service Unavailable` and the second with
+//! `429 Too Many Requests`, so the example runs without network access.
+use std::time::Duration;
+use surf::{
+    http::{Method, StatusCode},
+    middleware::{Middleware, Next},
+    Client, Request, Response,
+};
+use surf_retry::{RetryAttempt, RetryInfoExtractor, RetryMiddleware, TruncatedExponentialBackoff};
+use url::Url;
+use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+/// Logs each attempt, using the `RetryAttempt` extension set by `RetryInfoExtractor` to tell retries apart.
+#[derive(Debug)]
+struct AttemptLogger;
+
+#[surf::utils::async_trait]
+impl Middleware for AttemptLogger {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result<Response> {
+        match req.ext::<RetryAttempt>() {
+            Some(RetryAttempt(attempt)) => println!("retry {} of {}", attempt, req.url()),
+            None => println!("first attempt of {}", req.url()),
+        }
+        next.run(req, client).await
+    }
+}
+
+#[async_std::main]
+async fn main() -> surf::Result<()> {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(503).insert_header("X-Request-Id", "abc123"))
+        .up_to_n_times(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(429).insert_header("X-Request-Id", "def456"))
+        .up_to_n_times(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("Hello!"))
+        .mount(&mock_server)
+        .await;
+
+    // Retry up to 5 times with a wait growing from 50ms, doubling on each retry up to 1 second
+    let retry = RetryMiddleware::builder()
+        .max_retries(5)
+        .policy(TruncatedExponentialBackoff::new(
+            Duration::from_millis(50),
+            2.0,
+            Duration::from_secs(1),
+        ))
+        .build()
+        // Also retry 503 Service Unavailable, keeping the default 429 and 408
+        .only_retry_codes(vec![
+            StatusCode::TooManyRequests,
+            StatusCode::RequestTimeout,
+            StatusCode::ServiceUnavailable,
+        ])
+        // The first retry is made immediately, the following ones wait 100ms, 200ms, 400ms, 800ms
+        .with_fast_first_retry(true)
+        // Connect the retry log events to the server side request ids
+        .log_retry_response_header("X-Request-Id")
+        // Let middleware later in the chain know a request is a retry
+        .with_header_forwarding_to_next(true);
+
+    let client = Client::new()
+        .with(retry)
+        .with(RetryInfoExtractor)
+        .with(AttemptLogger);
+    let url = Url::parse(&format!("{}/", mock_server.uri()))?;
+    let mut res = client.send(Request::new(Method::Get, url)).await?;
+    assert_eq!(res.status(), 200);
+    println!("{}", res.body_string().await?);
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! Re-signs HMAC authenticated requests on every retry, as their signature covers a timestamp.
+//!
+//! A local `wiremock` server answers the first request with `429 Too Many Requests`, so the example runs without
+//! network access.
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use surf::{http::Method, Client, Request};
+use surf_retry::RetryMiddleware;
+use url::Url;
+use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+const KEY: &[u8] = b"secret key";
+
+/// Sign the method, path and timestamp of the request, leaving its body untouched.
+fn sign(req: &mut Request, now: DateTime<Utc>) {
+    let timestamp = now.timestamp().to_string();
+    let mut mac = Hmac::<Sha256>::new_varkey(KEY).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}\n{}\n{}", req.method(), req.url().path(), timestamp).as_bytes());
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    req.set_header("X-Timestamp", timestamp);
+    req.set_header("Authorization", format!("HMAC-SHA256 {}", signature));
+}
+
+#[async_std::main]
+async fn main() -> surf::Result<()> {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+        .up_to_n_times(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("Hello!"))
+        .mount(&mock_server)
+        .await;
+
+    let retry = RetryMiddleware::default().with_request_signing_on_retry(sign);
+    let client = Client::new().with(retry);
+    let url = Url::parse(&format!("{}/", mock_server.uri()))?;
+    let mut req = Request::new(Method::Get, url);
+    // The original request is signed by the caller, retries are signed again by the middleware
+    sign(&mut req, Utc::now());
+    let mut res = client.send(req).await?;
+    assert_eq!(res.status(), 200);
+    println!("{}", res.body_string().await?);
+    Ok(())
+}
@@ -0,0 +1,285 @@
+use crate::{AggressivePolicy, ExponentialBackoff, RetryMiddleware, RetryPolicy};
+use std::time::Duration;
+
+/// Builder for a [`RetryMiddleware`], created with [`RetryMiddleware::builder`].
+///
+/// Defaults to the same settings as [`RetryMiddleware::default`].
+#[derive(Debug)]
+pub struct RetryMiddlewareBuilder<T: RetryPolicy + Send + Sync + 'static = ExponentialBackoff> {
+    max_retries: u32,
+    policy: T,
+    fallback_interval: u64,
+}
+
+impl Default for RetryMiddlewareBuilder<ExponentialBackoff> {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            policy: ExponentialBackoff::builder().build_with_max_retries(3),
+            fallback_interval: 1,
+        }
+    }
+}
+
+impl RetryMiddleware<ExponentialBackoff> {
+    /// Construct the retry middleware using a builder.
+    ///
+    /// # Example
+    /// ```
+    /// use surf_retry::RetryMiddleware;
+    ///
+    /// let retry = RetryMiddleware::builder()
+    ///     .max_retries(5)
+    ///     .exponential_policy()
+    ///     .initial_ms(100)
+    ///     .max_ms(30_000)
+    ///     .done()
+    ///     .build();
+    /// ```
+    pub fn builder() -> RetryMiddlewareBuilder {
+        RetryMiddlewareBuilder::default()
+    }
+}
+
+impl<T: RetryPolicy + Send + Sync + 'static> RetryMiddlewareBuilder<T> {
+    /// Set the maximum number of retries.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Set the interval in seconds used when no retry interval could be determined.
+    pub fn fallback_interval(mut self, fallback_interval: u64) -> Self {
+        self.fallback_interval = fallback_interval;
+        self
+    }
+
+    /// Use the provided retry policy.
+    pub fn policy<P: RetryPolicy + Send + Sync + 'static>(
+        self,
+        policy: P,
+    ) -> RetryMiddlewareBuilder<P> {
+        RetryMiddlewareBuilder {
+            max_retries: self.max_retries,
+            policy,
+            fallback_interval: self.fallback_interval,
+        }
+    }
+
+    /// Configure an [`ExponentialBackoff`] policy in place, finish it with [`ExponentialBackoffPolicyBuilder::done`].
+    ///
+    /// Unless set otherwise the policy allows as many retries as the middleware.
+    pub fn exponential_policy(self) -> ExponentialBackoffPolicyBuilder {
+        ExponentialBackoffPolicyBuilder {
+            parent: RetryMiddlewareBuilder {
+                max_retries: self.max_retries,
+                policy: ExponentialBackoff::builder().build_with_max_retries(self.max_retries),
+                fallback_interval: self.fallback_interval,
+            },
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(30 * 60),
+            backoff_exponent: 3,
+            max_retries: None,
+        }
+    }
+
+    /// Retry `fast_retries` times after `fast_delay` before falling back to the configured policy, see
+    /// [`AggressivePolicy`].
+    pub fn aggressive(
+        self,
+        fast_retries: u32,
+        fast_delay: Duration,
+    ) -> RetryMiddlewareBuilder<AggressivePolicy<T>> {
+        RetryMiddlewareBuilder {
+            max_retries: self.max_retries,
+            policy: AggressivePolicy::new(fast_retries, fast_delay, self.policy),
+            fallback_interval: self.fallback_interval,
+        }
+    }
+
+    /// Build the retry middleware.
+    pub fn build(self) -> RetryMiddleware<T> {
+        RetryMiddleware::new(self.max_retries, self.policy, self.fallback_interval)
+    }
+}
+
+impl RetryMiddlewareBuilder<ExponentialBackoff> {
+    /// Set the middleware's max retries and the [`ExponentialBackoff`] policy's max retries to `n` at once.
+    ///
+    /// Only the middleware's limit stops retrying, retries past the policy's limit wait the fallback interval
+    /// instead of the backoff. This shortcut keeps them consistent, so every retry waits the backoff. Setting either
+    /// afterwards breaks the sync.
+    pub fn exponential_attempts(mut self, n: u32) -> Self {
+        self.max_retries = n;
+        self.policy.max_n_retries = n;
+        self
+    }
+
+    /// Set the wait before the first retry of the [`ExponentialBackoff`] policy to `n` whole seconds. _Default 1_.
+    ///
+    /// Each further retry multiplies the wait by the policy's backoff exponent. `base_seconds(1)` with a backoff
+    /// exponent of 2 has a base wait of 1 second for the first retry, 2 seconds for the second and 4 seconds for the
+    /// third. The policy multiplies the base wait by a random jitter between 0 and 3 and caps the result at the
+    /// maximum wait, so the actual wait before the first retry is anywhere up to 3 seconds. The default exponent
+    /// is 3, use [`RetryMiddlewareBuilder::exponential_policy`] to change it. The maximum wait is raised to `n`
+    /// seconds if it is shorter.
+    pub fn base_seconds(mut self, n: u32) -> Self {
+        let base = Duration::from_secs(n.into());
+        self.policy.min_retry_interval = base;
+        self.policy.max_retry_interval = self.policy.max_retry_interval.max(base);
+        self
+    }
+}
+
+/// Sub-builder for an [`ExponentialBackoff`] policy, created with [`RetryMiddlewareBuilder::exponential_policy`].
+#[derive(Debug)]
+pub struct ExponentialBackoffPolicyBuilder {
+    parent: RetryMiddlewareBuilder,
+    initial: Duration,
+    max: Duration,
+    backoff_exponent: u32,
+    max_retries: Option<u32>,
+}
+
+impl ExponentialBackoffPolicyBuilder {
+    /// Set the minimum wait between two retries in milliseconds. _Default 1s_.
+    pub fn initial_ms(mut self, initial: u64) -> Self {
+        self.initial = Duration::from_millis(initial);
+        self
+    }
+
+    /// Set the maximum wait between two retries in milliseconds. _Default 30m_.
+    pub fn max_ms(mut self, max: u64) -> Self {
+        self.max = Duration::from_millis(max);
+        self
+    }
+
+    /// Set the backoff exponent. _Default 3_.
+    pub fn backoff_exponent(mut self, backoff_exponent: u32) -> Self {
+        self.backoff_exponent = backoff_exponent;
+        self
+    }
+
+    /// Set the maximum number of retries allowed by the policy. _Defaults to the middleware's max retries_.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Finish the policy and return to the middleware builder.
+    ///
+    /// Panics if the initial wait is greater than the maximum wait.
+    pub fn done(self) -> RetryMiddlewareBuilder {
+        let max_retries = self.max_retries.unwrap_or(self.parent.max_retries);
+        let policy = ExponentialBackoff::builder()
+            .retry_bounds(self.initial, self.max)
+            .backoff_exponent(self.backoff_exponent)
+            .build_with_max_retries(max_retries);
+        self.parent.policy(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_policy_is_configured_in_place() {
+        let retry = RetryMiddleware::builder()
+            .max_retries(5)
+            .fallback_interval(2)
+            .exponential_policy()
+            .initial_ms(100)
+            .max_ms(30_000)
+            .backoff_exponent(2)
+            .done()
+            .build();
+        assert_eq!(retry.max_retries, 5);
+        assert_eq!(retry.fallback_interval, 2);
+        assert_eq!(retry.policy.max_n_retries, 5);
+        assert_eq!(retry.policy.min_retry_interval, Duration::from_millis(100));
+        assert_eq!(retry.policy.max_retry_interval, Duration::from_secs(30));
+        assert_eq!(retry.policy.backoff_exponent, 2);
+    }
+
+    #[test]
+    fn aggressive_wraps_configured_policy() {
+        let retry = RetryMiddleware::builder()
+            .exponential_attempts(5)
+            .aggressive(2, Duration::from_millis(50))
+            .build();
+        assert_eq!(retry.max_retries, 5);
+        assert_eq!(retry.policy.fast_retries, 2);
+        assert_eq!(retry.policy.fast_delay, Duration::from_millis(50));
+        assert_eq!(retry.policy.then.max_n_retries, 5);
+    }
+
+    #[test]
+    fn base_seconds_sets_first_wait() {
+        let retry = RetryMiddleware::builder().base_seconds(2).build();
+        assert_eq!(retry.policy.min_retry_interval, Duration::from_secs(2));
+        assert_eq!(
+            retry.policy.max_retry_interval,
+            Duration::from_secs(30 * 60)
+        );
+
+        let retry = RetryMiddleware::builder()
+            .exponential_policy()
+            .max_ms(1_000)
+            .done()
+            .base_seconds(5)
+            .build();
+        assert_eq!(retry.policy.min_retry_interval, Duration::from_secs(5));
+        assert_eq!(retry.policy.max_retry_interval, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn base_seconds_waits_stay_within_jitter() {
+        let retry = RetryMiddleware::builder()
+            .exponential_policy()
+            .backoff_exponent(2)
+            .done()
+            .base_seconds(1)
+            .build();
+        let schedule = retry.backoff_schedule(3);
+        for (wait, base) in schedule.iter().zip([1, 2, 4]) {
+            assert!(*wait <= Duration::from_secs(3 * base), "{:?}", schedule);
+        }
+    }
+
+    #[test]
+    fn exponential_attempts_sets_both_limits() {
+        let retry = RetryMiddleware::builder().exponential_attempts(7).build();
+        assert_eq!(retry.max_retries, 7);
+        assert_eq!(retry.policy.max_n_retries, 7);
+
+        let retry = RetryMiddleware::builder()
+            .exponential_policy()
+            .initial_ms(100)
+            .done()
+            .exponential_attempts(2)
+            .build();
+        assert_eq!(retry.max_retries, 2);
+        assert_eq!(retry.policy.max_n_retries, 2);
+        assert_eq!(retry.policy.min_retry_interval, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn exponential_attempts_wait_the_backoff_for_every_retry() {
+        let retry = RetryMiddleware::builder()
+            .fallback_interval(7)
+            .exponential_policy()
+            .initial_ms(10)
+            .max_ms(1_000)
+            .done()
+            .exponential_attempts(3)
+            .build();
+        let schedule = retry.backoff_schedule(3);
+        assert_eq!(schedule.len(), 3);
+        assert!(
+            schedule.iter().all(|wait| *wait <= Duration::from_secs(1)),
+            "{:?}",
+            schedule
+        );
+    }
+}
@@ -0,0 +1,17 @@
+use std::fmt;
+
+/// Connects the retry middleware to an external circuit breaker, see
+/// [`RetryMiddleware::with_circuit_breaker_integration`](crate::RetryMiddleware::with_circuit_breaker_integration).
+///
+/// Implement it for an adapter around `failsafe`, `governor` or a custom breaker to stop retrying while the
+/// breaker is open.
+pub trait CircuitBreakerGate: fmt::Debug {
+    /// Whether the circuit is open, in which case no further retries are made.
+    fn is_open(&self) -> bool;
+
+    /// Called after each attempt that failed or triggers a retry.
+    fn record_failure(&self);
+
+    /// Called after each attempt that completed without triggering a retry.
+    fn record_success(&self);
+}
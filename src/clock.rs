@@ -0,0 +1,71 @@
+use chrono::{DateTime, Utc};
+use std::{fmt, time::SystemTime};
+
+#[cfg(feature = "testing")]
+use std::sync::{Arc, Mutex};
+
+/// Source of the current time used when computing retry waits.
+///
+/// Note that policies like [`ExponentialBackoff`](crate::ExponentialBackoff) compute their retry time from the
+/// system clock themselves, so a custom clock mainly affects the evaluation of `Retry-After` headers.
+pub trait Clock: fmt::Debug {
+    /// The current time as a [`DateTime<Utc>`].
+    fn now_utc(&self) -> DateTime<Utc>;
+
+    /// The current time as a [`SystemTime`].
+    fn system_now(&self) -> SystemTime;
+}
+
+/// [`Clock`] backed by the system clock, used by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// [`Clock`] that only moves when told to, for deterministic tests of time dependent behavior.
+#[cfg(feature = "testing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    /// The time reported by the clock.
+    pub time: Arc<Mutex<DateTime<Utc>>>,
+}
+
+#[cfg(feature = "testing")]
+impl MockClock {
+    /// Construct a clock reporting the provided time.
+    pub fn new(time: DateTime<Utc>) -> Self {
+        Self {
+            time: Arc::new(Mutex::new(time)),
+        }
+    }
+
+    /// Set the time reported by the clock.
+    pub fn set(&self, time: DateTime<Utc>) {
+        *self.time.lock().unwrap() = time;
+    }
+
+    /// Move the time reported by the clock forward.
+    pub fn advance(&self, duration: chrono::Duration) {
+        *self.time.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Clock for MockClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        *self.time.lock().unwrap()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.now_utc().into()
+    }
+}
@@ -0,0 +1,59 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use surf::{http, middleware::Next, Client, Request, Response, Result};
+
+#[cfg(feature = "async-std")]
+use async_std::sync::Mutex;
+#[cfg(any(feature = "tokio", feature = "wasm"))]
+use tokio::sync::Mutex;
+
+/// The buffered response of a completed retry, shared with identical concurrent retries.
+type Slot = Arc<Mutex<Option<(http::Response, Vec<u8>)>>>;
+
+/// In-flight retries keyed by method, url and headers.
+pub(crate) type DeduplicationCache = Arc<DashMap<String, Slot>>;
+
+/// Send the retry unless an identical retry is already in flight, in which case its response is reused.
+pub(crate) async fn run(
+    cache: &DeduplicationCache,
+    req: Request,
+    client: Client,
+    next: Next<'_>,
+) -> Result<Response> {
+    let key = key(&req);
+    let slot = cache.entry(key.clone()).or_default().clone();
+    let mut shared = slot.lock().await;
+    if let Some((res, body)) = shared.as_ref() {
+        return Ok(rebuild(res, body));
+    }
+    let res = buffered(next.run(req, client).await).await;
+    if let Ok(res) = &res {
+        *shared = Some(res.clone());
+    }
+    // Later retries must reach the server again, only those waiting on this slot reuse its response
+    cache.remove_if(&key, |_, current| Arc::ptr_eq(current, &slot));
+    res.map(|(res, body)| rebuild(&res, &body))
+}
+
+/// Requests are identical if they have the same method, url and headers, so responses to requests with different
+/// credentials are never shared.
+fn key(req: &Request) -> String {
+    let mut headers: Vec<String> = req
+        .iter()
+        .map(|(name, values)| format!("{}: {:?}", name, values))
+        .collect();
+    headers.sort_unstable();
+    format!("{} {}\n{}", req.method(), req.url(), headers.join("\n"))
+}
+
+async fn buffered(res: Result<Response>) -> Result<(http::Response, Vec<u8>)> {
+    let mut res = res?;
+    let body = res.body_bytes().await?;
+    Ok((res.into(), body))
+}
+
+fn rebuild(res: &http::Response, body: &[u8]) -> Response {
+    let mut res = res.clone();
+    res.set_body(body.to_vec());
+    res.into()
+}
@@ -38,15 +38,62 @@
 //!
 //! Per default `async_std` is used for waiting in between retries. To enable `tokio`, the default features must be disabled. The `tokio` feature
 //! enables compilation as wasm. The same feature is also available as `wasm`.
-use chrono::Utc;
+//!
+//! The `testing` feature provides a `MockClock` for deterministic tests of time dependent behavior.
+//!
+//! The `typemap` feature allows boxing the middleware as `dyn Any` for storage in framework state stores.
+//!
+//! The `deduplication` feature allows sharing the response of a retry with identical retries running concurrently.
+//!
+//! The `serde` feature provides a `SerdePolicy` configured from JSON that can be updated at runtime.
+//!
+//! The `stale-cache` feature allows returning a stale cached response once the retries of a read-only request are exhausted or it failed with an error.
+mod builder;
+mod circuit_breaker;
+mod clock;
+#[cfg(feature = "deduplication")]
+mod deduplication;
+mod policies;
+#[cfg(feature = "serde")]
+mod serde_policy;
+mod stats;
+mod window;
+
+pub use builder::{ExponentialBackoffPolicyBuilder, RetryMiddlewareBuilder};
+use chrono::{DateTime, Utc};
+pub use circuit_breaker::CircuitBreakerGate;
+#[cfg(feature = "testing")]
+pub use clock::MockClock;
+pub use clock::{Clock, SystemClock};
+use futures_lite::io::{AsyncReadExt, Cursor};
 use httpdate::parse_http_date;
+pub use policies::{AggressivePolicy, TruncatedExponentialBackoff};
 pub use retry_policies::{policies::ExponentialBackoff, RetryPolicy};
-use std::time::{Duration, SystemTime};
+#[cfg(feature = "serde")]
+pub use serde_policy::{RetryConfig, SerdePolicy};
+pub use stats::{BackoffStatsRecorder, ExponentialBackoffStats};
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 use surf::{
-    http::{headers, StatusCode},
+    http::{
+        headers::{self, HeaderName, HeaderValues},
+        Method, StatusCode,
+    },
     middleware::{Middleware, Next},
-    Client, Request, Response, Result,
+    Body, Client, Request, Response, Result,
 };
+pub use window::RetryWindow;
 
 /// The middleware is constructed with settings to handle a few different situations.
 ///
@@ -55,11 +102,298 @@ use surf::{
 /// If no `Retry-After` header has been provided the configured [policy](https://docs.rs/retry-policies) will be used.
 ///
 /// Should conditions for a retry be met but a retry interval failed to be determined the provided `fallback_interval` will be used.
-#[derive(Debug)]
 pub struct RetryMiddleware<T: RetryPolicy + Send + Sync + 'static> {
     max_retries: u32,
     policy: T,
     fallback_interval: u64,
+    log_retry_response_headers: Vec<HeaderName>,
+    custom_wait_fn: Option<CustomWaitFn>,
+    fast_first_retry: bool,
+    forward_retry_attempt: bool,
+    clock: Arc<dyn Clock + Send + Sync>,
+    per_attempt_modifiers: Vec<RequestModifier>,
+    retry_codes: Vec<StatusCode>,
+    retry_body_patterns: Vec<String>,
+    max_response_body_for_predicate: usize,
+    delay_multiplier_from_header: bool,
+    max_delay_multiplier: f64,
+    drain_timeout: Option<Duration>,
+    endpoint_overrides: HashMap<(Method, String), u32>,
+    consecutive_failures: Arc<AtomicU32>,
+    consecutive_failure_threshold: Option<u32>,
+    consecutive_failure_multiplier: f64,
+    retry_upgrade_requests: bool,
+    before_sleep: Option<BeforeSleepFn>,
+    skip_sleep_on_last_retry: bool,
+    sticky_cookies: bool,
+    extract_response_headers: Vec<HeaderExtractor>,
+    quantization: Option<Duration>,
+    cooling_period: Option<Duration>,
+    last_failure: Arc<Mutex<Option<(SystemTime, Duration)>>>,
+    skip_retry_for_localhost: bool,
+    redact_headers: Vec<HeaderName>,
+    response_validator: Option<ResponseValidator>,
+    attempt_budget_from_server: bool,
+    signing_hook: Option<SigningHook>,
+    align_to_window: Option<Duration>,
+    window_alignment_tolerance: Duration,
+    circuit_breaker: Option<Arc<dyn CircuitBreakerGate + Send + Sync>>,
+    per_attempt_timeout: Option<Duration>,
+    timeout_escalation_factor: Option<f64>,
+    max_per_attempt_timeout: Option<Duration>,
+    policy_refresh: Option<PolicyRefreshFn>,
+    refresh_interval: Option<Duration>,
+    refreshed_policy: Arc<RwLock<Option<(SharedPolicy, Instant)>>>,
+    policy_refreshing: Arc<Mutex<()>>,
+    queue_depth_fn: Option<QueueDepthFn>,
+    max_queue_depth: Option<usize>,
+    queue_depth_multiplier: f64,
+    cancellation_token: Option<Arc<AtomicBool>>,
+    sse_retry_header: bool,
+    per_attempt_id_header: Option<(&'static str, AttemptIdFn)>,
+    parse_server_timing: bool,
+    health_check: Option<HealthCheckFn>,
+    rate_limit_remaining_threshold: Option<u32>,
+    max_rate_limit_pause: Duration,
+    age_based_escalation: Option<AgeEscalationFn>,
+    retry_window: Option<RetryWindowFn>,
+    retry_on_dns_error: bool,
+    retry_on_goaway: bool,
+    include_version_header: bool,
+    backoff_stats: Option<BackoffStatsRecorder>,
+    #[cfg(feature = "stale-cache")]
+    stale_cache: Option<StaleCacheFn>,
+    #[cfg(feature = "deduplication")]
+    deduplication_cache: Option<deduplication::DeduplicationCache>,
+}
+
+/// Computes the wait before a retry from the response and the retry attempt number.
+type CustomWaitFn = Arc<dyn Fn(&Response, u32) -> Duration + Send + Sync>;
+
+/// Transforms the wait right before sleeping.
+type BeforeSleepFn = Arc<dyn Fn(Duration) -> Duration + Send + Sync>;
+
+/// Decides whether a successful response is final.
+type ResponseValidator = Arc<dyn Fn(&Response) -> bool + Send + Sync>;
+
+/// Signs a retried request for the given time.
+type SigningHook = Arc<dyn Fn(&mut Request, DateTime<Utc>) + Send + Sync>;
+
+/// A retry policy shared between the middleware and the requests in flight.
+type SharedPolicy = Arc<dyn RetryPolicy + Send + Sync>;
+
+/// Loads the current retry policy, e.g. from remote configuration.
+type PolicyRefreshFn = Arc<dyn Fn() -> Box<dyn RetryPolicy + Send + Sync> + Send + Sync>;
+
+/// Reports the current depth of a local task queue.
+type QueueDepthFn = Arc<dyn Fn() -> usize + Send + Sync>;
+
+/// Generates a unique id for an attempt.
+type AttemptIdFn = Arc<dyn Fn() -> String + Send + Sync>;
+
+/// Checks whether the server recovered, using the client passed to the middleware.
+type HealthCheckFn = Arc<dyn Fn(Client) -> BoxFuture<'static, bool> + Send + Sync>;
+
+/// An owned dynamically typed [`Future`], as returned by the health check of
+/// [`RetryMiddleware::with_host_health_check`].
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Maps the age of a request to additional retries.
+type AgeEscalationFn = Arc<dyn Fn(Duration) -> u32 + Send + Sync>;
+
+/// Decides whether retries are allowed at the given time.
+type RetryWindowFn = Arc<dyn Fn(DateTime<Utc>) -> bool + Send + Sync>;
+
+/// Looks up a stale cached response for a request.
+#[cfg(feature = "stale-cache")]
+type StaleCacheFn = Arc<dyn Fn(&Request) -> Option<Response> + Send + Sync>;
+
+/// Parses a response header into a value of a specific type.
+type HeaderParser = Arc<dyn Fn(&HeaderValues, SystemTime) -> Option<AnyValue> + Send + Sync>;
+
+type AnyValue = Box<dyn Any + Send + Sync>;
+
+/// A response header to extract into the [`RetryContext`] and the type it is parsed into.
+#[derive(Clone)]
+struct HeaderExtractor {
+    name: HeaderName,
+    type_id: TypeId,
+    type_name: &'static str,
+    parse: HeaderParser,
+}
+
+impl fmt::Debug for HeaderExtractor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeaderExtractor")
+            .field("name", &self.name)
+            .field("type_id", &self.type_id)
+            .field("type_name", &self.type_name)
+            .finish()
+    }
+}
+
+/// Modifies a request before it is sent.
+type RequestModifier = Arc<dyn Fn(&mut Request) + Send + Sync>;
+
+impl<T: RetryPolicy + Send + Sync + fmt::Debug + 'static> fmt::Debug for RetryMiddleware<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("RetryMiddleware");
+        debug
+            .field("max_retries", &self.max_retries)
+            .field("policy", &self.policy)
+            .field("fallback_interval", &self.fallback_interval)
+            .field(
+                "log_retry_response_headers",
+                &self.log_retry_response_headers,
+            )
+            .field(
+                "custom_wait_fn",
+                &self
+                    .custom_wait_fn
+                    .as_ref()
+                    .map(|_| "Fn(&Response, u32) -> Duration"),
+            )
+            .field("fast_first_retry", &self.fast_first_retry)
+            .field("forward_retry_attempt", &self.forward_retry_attempt)
+            .field("clock", &self.clock)
+            .field(
+                "per_attempt_modifiers",
+                &format_args!("[{} modifiers]", self.per_attempt_modifiers.len()),
+            )
+            .field("retry_codes", &self.retry_codes)
+            .field("retry_body_patterns", &self.retry_body_patterns)
+            .field(
+                "max_response_body_for_predicate",
+                &self.max_response_body_for_predicate,
+            )
+            .field(
+                "delay_multiplier_from_header",
+                &self.delay_multiplier_from_header,
+            )
+            .field("max_delay_multiplier", &self.max_delay_multiplier)
+            .field("drain_timeout", &self.drain_timeout)
+            .field("endpoint_overrides", &self.endpoint_overrides)
+            .field("consecutive_failures", &self.consecutive_failures)
+            .field(
+                "consecutive_failure_threshold",
+                &self.consecutive_failure_threshold,
+            )
+            .field(
+                "consecutive_failure_multiplier",
+                &self.consecutive_failure_multiplier,
+            )
+            .field("retry_upgrade_requests", &self.retry_upgrade_requests)
+            .field(
+                "before_sleep",
+                &self
+                    .before_sleep
+                    .as_ref()
+                    .map(|_| "Fn(Duration) -> Duration"),
+            )
+            .field("skip_sleep_on_last_retry", &self.skip_sleep_on_last_retry)
+            .field("sticky_cookies", &self.sticky_cookies)
+            .field("extract_response_headers", &self.extract_response_headers)
+            .field("quantization", &self.quantization)
+            .field("cooling_period", &self.cooling_period)
+            .field("last_failure", &self.last_failure)
+            .field("skip_retry_for_localhost", &self.skip_retry_for_localhost)
+            .field("redact_headers", &self.redact_headers)
+            .field(
+                "response_validator",
+                &self
+                    .response_validator
+                    .as_ref()
+                    .map(|_| "Fn(&Response) -> bool"),
+            )
+            .field(
+                "attempt_budget_from_server",
+                &self.attempt_budget_from_server,
+            )
+            .field(
+                "signing_hook",
+                &self
+                    .signing_hook
+                    .as_ref()
+                    .map(|_| "Fn(&mut Request, DateTime<Utc>)"),
+            )
+            .field("align_to_window", &self.align_to_window)
+            .field(
+                "window_alignment_tolerance",
+                &self.window_alignment_tolerance,
+            )
+            .field("circuit_breaker", &self.circuit_breaker)
+            .field("per_attempt_timeout", &self.per_attempt_timeout)
+            .field("timeout_escalation_factor", &self.timeout_escalation_factor)
+            .field("max_per_attempt_timeout", &self.max_per_attempt_timeout)
+            .field(
+                "policy_refresh",
+                &self
+                    .policy_refresh
+                    .as_ref()
+                    .map(|_| "Fn() -> Box<dyn RetryPolicy>"),
+            )
+            .field("refresh_interval", &self.refresh_interval)
+            .field(
+                "queue_depth_fn",
+                &self.queue_depth_fn.as_ref().map(|_| "Fn() -> usize"),
+            )
+            .field("max_queue_depth", &self.max_queue_depth)
+            .field("queue_depth_multiplier", &self.queue_depth_multiplier)
+            .field("cancellation_token", &self.cancellation_token)
+            .field("sse_retry_header", &self.sse_retry_header)
+            .field(
+                "per_attempt_id_header",
+                &self
+                    .per_attempt_id_header
+                    .as_ref()
+                    .map(|(name, _)| (name, "Fn() -> String")),
+            )
+            .field("parse_server_timing", &self.parse_server_timing)
+            .field(
+                "health_check",
+                &self
+                    .health_check
+                    .as_ref()
+                    .map(|_| "Fn(Client) -> BoxFuture<'static, bool>"),
+            )
+            .field(
+                "rate_limit_remaining_threshold",
+                &self.rate_limit_remaining_threshold,
+            )
+            .field("max_rate_limit_pause", &self.max_rate_limit_pause)
+            .field(
+                "age_based_escalation",
+                &self
+                    .age_based_escalation
+                    .as_ref()
+                    .map(|_| "Fn(Duration) -> u32"),
+            )
+            .field(
+                "retry_window",
+                &self
+                    .retry_window
+                    .as_ref()
+                    .map(|_| "Fn(DateTime<Utc>) -> bool"),
+            )
+            .field("retry_on_dns_error", &self.retry_on_dns_error)
+            .field("retry_on_goaway", &self.retry_on_goaway)
+            .field("include_version_header", &self.include_version_header)
+            .field("backoff_stats", &self.backoff_stats);
+        #[cfg(feature = "stale-cache")]
+        debug.field(
+            "stale_cache",
+            &self
+                .stale_cache
+                .as_ref()
+                .map(|_| "Fn(&Request) -> Option<Response>"),
+        );
+        #[cfg(feature = "deduplication")]
+        debug.field(
+            "response_deduplication",
+            &self.deduplication_cache.is_some(),
+        );
+        debug.finish()
+    }
 }
 
 impl Default for RetryMiddleware<ExponentialBackoff> {
@@ -79,113 +413,3313 @@ impl<T: RetryPolicy + Send + Sync + 'static> RetryMiddleware<T> {
             max_retries,
             policy,
             fallback_interval,
+            log_retry_response_headers: Vec::new(),
+            custom_wait_fn: None,
+            fast_first_retry: false,
+            forward_retry_attempt: false,
+            clock: Arc::new(SystemClock),
+            per_attempt_modifiers: Vec::new(),
+            retry_codes: RETRY_CODES.to_vec(),
+            retry_body_patterns: Vec::new(),
+            max_response_body_for_predicate: 64 * 1024,
+            delay_multiplier_from_header: false,
+            max_delay_multiplier: 10.0,
+            drain_timeout: None,
+            endpoint_overrides: HashMap::new(),
+            consecutive_failures: Arc::new(AtomicU32::new(0)),
+            consecutive_failure_threshold: None,
+            consecutive_failure_multiplier: 1.0,
+            retry_upgrade_requests: true,
+            before_sleep: None,
+            skip_sleep_on_last_retry: false,
+            sticky_cookies: false,
+            extract_response_headers: Vec::new(),
+            quantization: None,
+            cooling_period: None,
+            last_failure: Arc::new(Mutex::new(None)),
+            skip_retry_for_localhost: false,
+            redact_headers: vec![
+                headers::AUTHORIZATION,
+                headers::COOKIE,
+                HeaderName::from("X-Api-Key"),
+                headers::SET_COOKIE,
+            ],
+            response_validator: None,
+            attempt_budget_from_server: false,
+            signing_hook: None,
+            align_to_window: None,
+            window_alignment_tolerance: Duration::ZERO,
+            circuit_breaker: None,
+            per_attempt_timeout: None,
+            timeout_escalation_factor: None,
+            max_per_attempt_timeout: None,
+            policy_refresh: None,
+            refresh_interval: None,
+            refreshed_policy: Arc::new(RwLock::new(None)),
+            policy_refreshing: Arc::new(Mutex::new(())),
+            queue_depth_fn: None,
+            max_queue_depth: None,
+            queue_depth_multiplier: 2.0,
+            cancellation_token: None,
+            sse_retry_header: false,
+            per_attempt_id_header: None,
+            parse_server_timing: false,
+            health_check: None,
+            rate_limit_remaining_threshold: None,
+            max_rate_limit_pause: Duration::from_secs(60),
+            age_based_escalation: None,
+            retry_window: None,
+            retry_on_dns_error: false,
+            retry_on_goaway: true,
+            include_version_header: false,
+            backoff_stats: None,
+            #[cfg(feature = "stale-cache")]
+            stale_cache: None,
+            #[cfg(feature = "deduplication")]
+            deduplication_cache: None,
+        }
+    }
+
+    /// Replace the `Retry-After`, policy and fallback wait computation with a custom function.
+    ///
+    /// The function receives the response that triggered the retry and the retry attempt number (starting at 1).
+    pub fn with_custom_wait_computation(
+        mut self,
+        wait_fn: impl Fn(&Response, u32) -> Duration + Send + Sync + 'static,
+    ) -> Self {
+        self.custom_wait_fn = Some(Arc::new(wait_fn));
+        self
+    }
+
+    /// Make the first retry immediately, without waiting.
+    ///
+    /// Transient failures are often resolved by a single immediate retry, subsequent retries wait as usual.
+    /// This takes precedence over the `Retry-After` header and a custom wait computation for the first retry.
+    pub fn with_fast_first_retry(mut self, fast_first_retry: bool) -> Self {
+        self.fast_first_retry = fast_first_retry;
+        self
+    }
+
+    /// Use the provided [`Clock`] instead of the system clock for `Retry-After` dates, retry windows and signing.
+    ///
+    /// Waits of the retry policy are measured against the system clock, as policies compute their retry time
+    /// from it.
+    pub fn with_local_clock(mut self, clock: impl Clock + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Add a modifier applied to the request before the attempt matching its position.
+    ///
+    /// The first modifier added applies to the initial attempt, the second to the first retry and so on.
+    /// Attempts beyond the number of modifiers reuse the last one, e.g. for authentication protocols requiring a
+    /// fresh token on every retry.
+    pub fn add_per_attempt_modifier(
+        mut self,
+        modifier: impl Fn(&mut Request) + Send + Sync + 'static,
+    ) -> Self {
+        self.per_attempt_modifiers.push(Arc::new(modifier));
+        self
+    }
+
+    /// Only retry responses with one of the provided status codes.
+    ///
+    /// This replaces the default codes (`429 Too Many Requests` and `408 Request Timeout`) rather than adding to them,
+    /// include them in `codes` to keep retrying them. This and [`RetryMiddleware::use_default_retry_codes`] are
+    /// mutually exclusive, whichever is called last determines the codes that are retried.
+    pub fn only_retry_codes(mut self, codes: Vec<StatusCode>) -> Self {
+        self.retry_codes = codes;
+        self
+    }
+
+    /// Retry the default status codes, `429 Too Many Requests` and `408 Request Timeout`.
+    ///
+    /// Undoes a previous call to [`RetryMiddleware::only_retry_codes`].
+    pub fn use_default_retry_codes(mut self) -> Self {
+        self.retry_codes = RETRY_CODES.to_vec();
+        self
+    }
+
+    /// Also retry responses whose body contains `pattern`, regardless of their status code.
+    ///
+    /// Some APIs signal errors like rate limiting with a `200 OK` and a body like `error: rate_limit_exceeded`.
+    /// Only the first [`max_response_body_for_predicate`](RetryMiddleware::max_response_body_for_predicate) bytes
+    /// of the body are searched, the response returned to the caller still carries the complete body. Reading
+    /// stops as soon as a pattern is found. Empty patterns would match every response and are ignored.
+    pub fn retry_if_body_contains(mut self, pattern: &str) -> Self {
+        if !pattern.is_empty() {
+            self.retry_body_patterns.push(pattern.to_owned());
+        }
+        self
+    }
+
+    /// Set the number of response body bytes buffered to search for body patterns. _Default 64KiB_.
+    pub fn max_response_body_for_predicate(mut self, max_bytes: usize) -> Self {
+        self.max_response_body_for_predicate = max_bytes;
+        self
+    }
+
+    /// Multiply the computed wait by the factor a server suggests in the non-standard [`BACKOFF_MULTIPLIER_HEADER`].
+    ///
+    /// Only finite, positive multipliers are applied and they are capped at
+    /// [`max_delay_multiplier`](RetryMiddleware::max_delay_multiplier).
+    ///
+    /// # Security
+    ///
+    /// The server controls how long the client waits. Without the cap a hostile or misconfigured server could
+    /// stall requests indefinitely, keep the cap low.
+    pub fn with_delay_multiplier_from_header(mut self, enabled: bool) -> Self {
+        self.delay_multiplier_from_header = enabled;
+        self
+    }
+
+    /// Set the largest multiplier accepted from the [`BACKOFF_MULTIPLIER_HEADER`]. _Default 10_.
+    pub fn max_delay_multiplier(mut self, max: f64) -> Self {
+        self.max_delay_multiplier = max;
+        self
+    }
+
+    /// Retry requests failing because the server closed a kept-alive connection, waiting `drain_timeout` before the first retry.
+    ///
+    /// A closed connection is detected by an [`io::Error`](std::io::Error) of kind `ConnectionReset`,
+    /// `ConnectionAborted` or `BrokenPipe` in the error's source chain. The extra wait gives the connection pool
+    /// time to drop the stale connections and is added to the regular retry wait, which is determined by the
+    /// policy as there is no response. It is only added until a retry is sent after it. The error does not tell
+    /// whether the failed attempt used a reused or a fresh connection, so resets on fresh connections wait as well.
+    pub fn with_connection_pool_drain_wait(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = Some(drain_timeout);
+        self
+    }
+
+    /// Allow a different number of retries for requests with the given method and a path starting with `path_prefix`.
+    ///
+    /// When several prefixes match a request the longest one wins, requests matching no prefix use the
+    /// middleware's `max_retries`.
+    pub fn override_max_retries_for(
+        mut self,
+        method: Method,
+        path_prefix: impl Into<String>,
+        max_retries: u32,
+    ) -> Self {
+        self.endpoint_overrides
+            .insert((method, path_prefix.into()), max_retries);
+        self
+    }
+
+    /// Multiply waits computed by the policy by `multiplier` while at least `threshold` consecutive attempts failed.
+    ///
+    /// Failures are counted across all requests sent through this middleware and the count is reset by any
+    /// successful response, backing off globally while a server is struggling.
+    pub fn with_policy_adjustment_on_consecutive_failures(
+        mut self,
+        threshold: u32,
+        multiplier: f64,
+    ) -> Self {
+        self.consecutive_failure_threshold = Some(threshold);
+        self.consecutive_failure_multiplier = multiplier;
+        self
+    }
+
+    /// Whether to retry protocol upgrade requests (e.g. WebSocket), sent with a `Connection: Upgrade` header. _Default true_.
+    ///
+    /// Depending on the protocol retrying a failed upgrade can have unintended side effects, disable to return
+    /// the response of the first attempt regardless of its status code.
+    pub fn with_retry_upgrade_requests(mut self, retry_upgrade_requests: bool) -> Self {
+        self.retry_upgrade_requests = retry_upgrade_requests;
+        self
+    }
+
+    /// Transform the wait before each retry after all other computations, e.g. to clamp or round it.
+    ///
+    /// The hook receives the final computed wait and returns the duration actually slept. It runs synchronously
+    /// right before sleeping.
+    pub fn with_before_sleep_hook(
+        mut self,
+        hook: impl Fn(Duration) -> Duration + Send + Sync + 'static,
+    ) -> Self {
+        self.before_sleep = Some(Arc::new(hook));
+        self
+    }
+
+    /// Make the last allowed retry immediately instead of waiting for it.
+    ///
+    /// Reduces the total time spent on requests that exhaust their retries, at the cost of giving the server less
+    /// time to recover before the final attempt.
+    pub fn with_no_sleep_on_last_retry(mut self, skip_sleep_on_last_retry: bool) -> Self {
+        self.skip_sleep_on_last_retry = skip_sleep_on_last_retry;
+        self
+    }
+
+    /// Send cookies set by a response that triggered a retry with the retried requests.
+    ///
+    /// The name and value of each `Set-Cookie` header are added to the request's `Cookie` header, replacing
+    /// cookies of the same name. This keeps load balanced backends routing the retries to the same session.
+    /// Cookie attributes like `Path` or `Expires` are not evaluated.
+    pub fn with_sticky_session_cookie(mut self, sticky_cookies: bool) -> Self {
+        self.sticky_cookies = sticky_cookies;
+        self
+    }
+
+    /// Round every wait up to the next multiple of `quantization`, e.g. a wait of 7 seconds becomes 10 seconds with
+    /// a quantization of 5 seconds.
+    ///
+    /// Aligns retries of different clients to fixed boundaries. Applied before the hook set with
+    /// [`RetryMiddleware::with_before_sleep_hook`]. A zero quantization leaves the waits unchanged.
+    pub fn with_wait_quantization(mut self, quantization: Duration) -> Self {
+        self.quantization = Some(quantization);
+        self
+    }
+
+    /// Keep the policy from restarting its backoff while a previous failure is less than `cooling_period` ago.
+    ///
+    /// The policy computes its wait from the retries made for the current request only, so a failure following a
+    /// burst of successes starts again with the shortest wait. Within the cooling period after a failure, the wait
+    /// computed by the policy is raised to the policy wait used for that failure, across all requests.
+    pub fn with_policy_cooling_period(mut self, cooling_period: Duration) -> Self {
+        self.cooling_period = Some(cooling_period);
+        self
+    }
+
+    /// Never retry requests to `localhost` or `127.0.0.1`, a convenience for tests against local mock servers.
+    ///
+    /// Retries against a mock server slow tests down and interfere with expectations on the number of received
+    /// requests.
+    ///
+    /// **Never enable this in production configuration**, services reached through a local proxy or sidecar
+    /// would silently lose their retries.
+    pub fn with_mock_server_detection(mut self, skip_retry_for_localhost: bool) -> Self {
+        self.skip_retry_for_localhost = skip_retry_for_localhost;
+        self
+    }
+
+    /// Retry successful responses the validator does not consider final, e.g. a `200 OK` whose body reports the
+    /// operation as still pending.
+    ///
+    /// The validator returns `true` for final responses and `false` for responses to retry like a status code from
+    /// the retried codes. It is only called for responses with a success status.
+    pub fn with_response_validator(
+        mut self,
+        validator: impl Fn(&Response) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.response_validator = Some(Arc::new(validator));
+        self
+    }
+
+    /// Limit the retries to the number a server announces in the [`MAX_CLIENT_RETRIES_HEADER`] of the first `429`
+    /// response, if it is lower than the configured max retries.
+    pub fn with_attempt_budget_from_server(mut self, attempt_budget_from_server: bool) -> Self {
+        self.attempt_budget_from_server = attempt_budget_from_server;
+        self
+    }
+
+    /// Sign every retried request again with the current time, for APIs whose request signatures cover a
+    /// timestamp and expire.
+    ///
+    /// The hook is called right before each retry is sent, after the per attempt modifiers, and should replace
+    /// the timestamp dependent headers (e.g. `X-Timestamp` and `Authorization`). The original request is sent as
+    /// signed by the caller. The hook may be called several times for the same request and must not consume or
+    /// alter its body. See `examples/signing.rs` for an HMAC signing implementation.
+    pub fn with_request_signing_on_retry(
+        mut self,
+        hook: impl Fn(&mut Request, DateTime<Utc>) + Send + Sync + 'static,
+    ) -> Self {
+        self.signing_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Delay retries to the start of the next rate limit window, for servers resetting their limits every `window`
+    /// (e.g. every minute on the minute).
+    ///
+    /// A retry due at most `tolerance` before a window boundary waits for the boundary, so it does not fail again
+    /// at the end of the exhausted window. Boundaries are multiples of `window` since the Unix epoch.
+    pub fn with_rate_limit_window_alignment(
+        mut self,
+        window: Duration,
+        tolerance: Duration,
+    ) -> Self {
+        self.align_to_window = Some(window);
+        self.window_alignment_tolerance = tolerance;
+        self
+    }
+
+    /// Report the outcome of every attempt to an external circuit breaker and stop retrying while it is open.
+    ///
+    /// The breaker is asked before each retry, if it is open the current response or error is returned.
+    pub fn with_circuit_breaker_integration(
+        mut self,
+        breaker: Arc<dyn CircuitBreakerGate + Send + Sync>,
+    ) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// Abort attempts that take longer than `timeout` and retry them.
+    ///
+    /// The timeout covers the attempt through the rest of the middleware chain until the response head is received.
+    pub fn with_per_attempt_timeout(mut self, timeout: Duration) -> Self {
+        self.per_attempt_timeout = Some(timeout);
+        self
+    }
+
+    /// Multiply the timeout set with [`RetryMiddleware::with_per_attempt_timeout`] by `factor` for each retry, up to
+    /// `max_per_attempt_timeout`.
+    ///
+    /// The timeout counterpart to exponential backoff: a server under load responds slower, so later attempts get a
+    /// larger budget. With a timeout of 5 seconds and a factor of 2 the first retry allows 10 seconds.
+    pub fn with_timeout_escalation(
+        mut self,
+        factor: f64,
+        max_per_attempt_timeout: Duration,
+    ) -> Self {
+        self.timeout_escalation_factor = Some(factor);
+        self.max_per_attempt_timeout = Some(max_per_attempt_timeout);
+        self
+    }
+
+    /// Use the policy returned by `refresh` instead of the configured policy, loading it again once it is older
+    /// than `refresh_interval`.
+    ///
+    /// For policies backed by remote configuration like feature flags. The refresh runs on the first retry wait
+    /// computed after the interval passed. Waits computed while another request refreshes the policy use the
+    /// previously loaded policy instead of blocking, or the configured policy before the first refresh completed.
+    pub fn with_policy_refresh_interval(
+        mut self,
+        refresh_interval: Duration,
+        refresh: impl Fn() -> Box<dyn RetryPolicy + Send + Sync> + Send + Sync + 'static,
+    ) -> Self {
+        self.policy_refresh = Some(Arc::new(refresh));
+        self.refresh_interval = Some(refresh_interval);
+        self
+    }
+
+    /// Adjust waits to the backpressure of a local task queue whose depth is reported by `queue_depth`.
+    ///
+    /// While the depth exceeds `max_queue_depth` waits are multiplied by the
+    /// [`RetryMiddleware::queue_depth_multiplier`], so retries do not add to the overload. While the queue is
+    /// empty waits computed by the policy are limited to its wait for the first retry, waits the server asked for
+    /// with `Retry-After` or another header are kept.
+    pub fn with_delay_from_queue_depth(
+        mut self,
+        queue_depth: impl Fn() -> usize + Send + Sync + 'static,
+        max_queue_depth: usize,
+    ) -> Self {
+        self.queue_depth_fn = Some(Arc::new(queue_depth));
+        self.max_queue_depth = Some(max_queue_depth);
+        self
+    }
+
+    /// Set the multiplier for waits while the queue is over its maximum depth. _Default 2_.
+    pub fn queue_depth_multiplier(mut self, queue_depth_multiplier: f64) -> Self {
+        self.queue_depth_multiplier = queue_depth_multiplier;
+        self
+    }
+
+    /// Stop retrying once `token` is set to `true`, e.g. when the user cancels the operation the request belongs to.
+    ///
+    /// The token is checked before each retry and while waiting for it, at least every
+    /// [`CANCELLATION_POLL_INTERVAL`]. If it is set, a [`RetryCancelled`] error is returned instead of the response or
+    /// error of the last attempt.
+    pub fn with_cancellation_token(mut self, token: Arc<AtomicBool>) -> Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
+    /// Wait the reconnection time a Server-Sent Events stream announces in its `retry:` field before retrying it.
+    ///
+    /// Applies to retried responses with a `text/event-stream` content type. The stream is read up to its first
+    /// `retry:` field or the end of its first event, at most [`RetryMiddleware::max_response_body_for_predicate`]
+    /// bytes, and the last `retry:` field read is used in place of the computed wait. Streams without one fall back
+    /// to the usual wait.
+    ///
+    /// **Experimental**: surf has no Server-Sent Events support of its own, this may change once it does.
+    pub fn with_sse_reconnect_delay(mut self, sse_retry_header: bool) -> Self {
+        self.sse_retry_header = sse_retry_header;
+        self
+    }
+
+    /// Set the `header` of every attempt, including the first one, to a new id returned by `generate`.
+    ///
+    /// Lets tracing systems correlate each attempt on its own. A session level id set on the request by the caller
+    /// is sent unchanged with every attempt and ties the attempts together.
+    pub fn with_per_retry_request_id(
+        mut self,
+        header: &'static str,
+        generate: impl Fn() -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.per_attempt_id_header = Some((header, Arc::new(generate)));
+        self
+    }
+
+    /// Wait the duration of the `wait` metric in a response's `Server-Timing` header, e.g.
+    /// `Server-Timing: wait;dur=500` for 500 milliseconds.
+    ///
+    /// Used for responses without a `Retry-After` header, responses without the metric fall back to the policy.
+    pub fn with_server_sent_wait(mut self, parse_server_timing: bool) -> Self {
+        self.parse_server_timing = parse_server_timing;
+        self
+    }
+
+    /// Check that the server recovered before each retry, e.g. by requesting its `/health` endpoint.
+    ///
+    /// The check runs before waiting for a retry. If it returns `false` the retry is not sent but still counts
+    /// towards the max retries, and the check runs again before the next one. It receives the client the middleware
+    /// runs in to reuse its connections. surf removes all middleware from the client it passes to middleware, so
+    /// health check requests skip this and every other middleware, like logging or authentication, and are never
+    /// retried.
+    ///
+    /// Note that this doubles the number of requests made while a server is failing.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use surf_retry::RetryMiddleware;
+    ///
+    /// let retry = RetryMiddleware::default().with_host_health_check(|client| {
+    ///     Box::pin(async move {
+    ///         match client.get("https://example.api/health").await {
+    ///             Ok(res) => res.status().is_success(),
+    ///             Err(_) => false,
+    ///         }
+    ///     })
+    /// });
+    /// ```
+    pub fn with_host_health_check(
+        mut self,
+        health_check: impl Fn(Client) -> BoxFuture<'static, bool> + Send + Sync + 'static,
+    ) -> Self {
+        self.health_check = Some(Arc::new(health_check));
+        self
+    }
+
+    /// Pause before returning a successful response whose [`RATE_LIMIT_REMAINING_HEADER`] is at or below
+    /// `threshold`, so the next request does not run into a `429`.
+    ///
+    /// The pause lasts until the time in the [`RATE_LIMIT_RESET_HEADER`], given either in seconds or as a Unix
+    /// timestamp, or the policy's wait for a first retry if the header is missing. It is capped at
+    /// [`max_rate_limit_pause`](RetryMiddleware::max_rate_limit_pause).
+    ///
+    /// # Security
+    ///
+    /// The server controls how long the client pauses. Without the cap a hostile or misconfigured server could
+    /// stall responses indefinitely, keep the cap low.
+    pub fn with_rate_limit_remaining_threshold(mut self, threshold: u32) -> Self {
+        self.rate_limit_remaining_threshold = Some(threshold);
+        self
+    }
+
+    /// Set the longest pause taken for a low [`RATE_LIMIT_REMAINING_HEADER`]. _Default 60s_.
+    pub fn max_rate_limit_pause(mut self, max: Duration) -> Self {
+        self.max_rate_limit_pause = max;
+        self
+    }
+
+    /// Allow additional retries for requests that have been retried for a while, as returned by `escalation` for
+    /// the time since the middleware received the request.
+    ///
+    /// In systems with task queues older requests have waited longest and deserve more attempts than new ones.
+    /// Requests excluded from retries, see [`RetryMiddleware::with_retry_upgrade_requests`] and
+    /// [`RetryMiddleware::with_mock_server_detection`], are never escalated.
+    pub fn with_priority_escalation(
+        mut self,
+        escalation: impl Fn(Duration) -> u32 + Send + Sync + 'static,
+    ) -> Self {
+        self.age_based_escalation = Some(Arc::new(escalation));
+        self
+    }
+
+    /// Allow one additional retry for every `every` since the middleware received the request, see
+    /// [`RetryMiddleware::with_priority_escalation`].
+    pub fn with_linear_priority_escalation(self, every: Duration) -> Self {
+        self.with_priority_escalation(move |age| {
+            if every.is_zero() {
+                0
+            } else {
+                // Float to int casts saturate
+                (age.as_secs_f64() / every.as_secs_f64()) as u32
+            }
+        })
+    }
+
+    /// Only retry while `window` returns `true` for the current time, e.g. during business hours or outside of
+    /// maintenance windows. See [`RetryWindow`] for daily windows.
+    ///
+    /// The window is checked before each retry, outside of it the current response or error is returned.
+    pub fn with_retry_window(
+        mut self,
+        window: impl Fn(DateTime<Utc>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.retry_window = Some(Arc::new(window));
+        self
+    }
+
+    /// Retry requests failing to resolve the host name, waiting as computed by the policy.
+    ///
+    /// DNS failures are usually transient, e.g. while a resolver restarts. surf does not expose a dedicated error
+    /// type for them, so the error and its sources are matched by message:
+    /// - `async-h1` (`h1-client`) resolves through the standard library, failing with an [`std::io::Error`] reading
+    ///   `failed to lookup address information`.
+    /// - `isahc` (`curl-client`) fails with a [`std::io::Error`] wrapping curl's `Couldn't resolve host name`.
+    /// - Errors of resolvers like `trust-dns` mention `dns error` or `no record found`.
+    pub fn with_retry_on_dns_error(mut self, retry_on_dns_error: bool) -> Self {
+        self.retry_on_dns_error = retry_on_dns_error;
+        self
+    }
+
+    /// Retry requests cancelled by an HTTP/2 `GOAWAY` frame immediately. _Default true_.
+    ///
+    /// A server shutting down gracefully cancels the requests it did not process, so they are always safe to retry.
+    /// As the cancellation is a connection level event and not a sign of overload, no wait is applied. The error
+    /// and its sources are matched by message, covering `GOAWAY` and `REFUSED_STREAM` errors of curl (`isahc`) and
+    /// `h2` (`hyper`) based backends.
+    pub fn with_http2_goaway_retry(mut self, retry_on_goaway: bool) -> Self {
+        self.retry_on_goaway = retry_on_goaway;
+        self
+    }
+
+    /// Collect statistics of the waits before retries into `stats`, e.g. for capacity planning.
+    ///
+    /// Every wait is recorded right before sleeping, after all adjustments to it. Read the statistics with
+    /// [`RetryMiddleware::backoff_stats`] or from a clone of `stats` kept after adding the middleware to a client.
+    pub fn with_exponential_backoff_stats(mut self, stats: BackoffStatsRecorder) -> Self {
+        self.backoff_stats = Some(stats);
+        self
+    }
+
+    /// The statistics of the waits so far, `None` if no retries have occurred or they are not collected, see
+    /// [`RetryMiddleware::with_exponential_backoff_stats`].
+    pub fn backoff_stats(&self) -> Option<ExponentialBackoffStats> {
+        self.backoff_stats
+            .as_ref()
+            .and_then(BackoffStatsRecorder::get)
+    }
+
+    /// Parse the header from responses triggering a retry and store it in the [`RetryContext`] of the final response.
+    ///
+    /// The value is parsed with [`FromStr`], headers that are missing or fail to parse are skipped. When several
+    /// responses carry the header the value from the latest one is kept.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use surf_retry::{RetryContext, RetryMiddleware};
+    /// use surf::Client;
+    ///
+    /// # async fn run() -> surf::Result<()> {
+    /// let retry = RetryMiddleware::default().extract_response_header::<u64>("X-Rate-Limit-Window");
+    /// let res = Client::new().with(retry).get("https://example.api").await?;
+    /// if let Some(context) = res.ext::<RetryContext>() {
+    ///     let window: Option<&u64> = context.get("X-Rate-Limit-Window");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn extract_response_header<V: FromStr + Send + Sync + 'static>(
+        mut self,
+        name: impl Into<HeaderName>,
+    ) -> Self {
+        self.extract_response_headers.push(HeaderExtractor {
+            name: name.into(),
+            type_id: TypeId::of::<V>(),
+            type_name: std::any::type_name::<V>(),
+            parse: Arc::new(|values, _| {
+                let value: AnyValue = Box::new(values.as_str().trim().parse::<V>().ok()?);
+                Some(value)
+            }),
+        });
+        self
+    }
+
+    /// Store the wait requested by the `Retry-After` header as a [`Duration`] in the [`RetryContext`].
+    pub fn extract_retry_after(mut self) -> Self {
+        self.extract_response_headers.push(HeaderExtractor {
+            name: headers::RETRY_AFTER,
+            type_id: TypeId::of::<Duration>(),
+            type_name: std::any::type_name::<Duration>(),
+            parse: Arc::new(|values, now| {
+                let secs = retry_to_seconds(values.last(), now).ok()?;
+                let value: AnyValue = Box::new(Duration::from_secs(secs));
+                Some(value)
+            }),
+        });
+        self
+    }
+
+    /// Return the response `cache` holds for a read-only request (`GET`, `HEAD` or `OPTIONS`) once its retries are
+    /// exhausted or it failed with an error, instead of the last response or error.
+    ///
+    /// Stale responses are marked with an `X-Stale: true` header, callers must check for it before treating the
+    /// response as current. A warning is logged whenever a stale response is served.
+    #[cfg(feature = "stale-cache")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "stale-cache")))]
+    pub fn with_response_cache_on_exhaustion(
+        mut self,
+        cache: impl Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+    ) -> Self {
+        self.stale_cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Share the response of a retry with identical retries running concurrently.
+    ///
+    /// When multiple tasks using the same client retry the same request at the same time, only the first one
+    /// reaches the server, the others wait for it to complete and receive a copy of its response.
+    /// The response body is buffered to be shared. Retries are identical if they have the same method, url and
+    /// headers, including `Authorization` and `Cookie`, so responses are only shared between requests made with the
+    /// same credentials. Headers that change with every attempt, like those of
+    /// [`RetryMiddleware::with_per_retry_request_id`], prevent any sharing.
+    #[cfg(feature = "deduplication")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "deduplication")))]
+    pub fn with_response_deduplication(mut self, deduplicate: bool) -> Self {
+        self.deduplication_cache = deduplicate.then(Default::default);
+        self
+    }
+
+    /// Set the [`RETRY_ATTEMPT_HEADER`] on retried requests so middleware later in the chain can tell the request is a retry.
+    ///
+    /// Add a [`RetryInfoExtractor`] after any middleware interested in the header, it moves the attempt number into
+    /// the request extensions and keeps the header from being sent to the server.
+    pub fn with_header_forwarding_to_next(mut self, forward_retry_attempt: bool) -> Self {
+        self.forward_retry_attempt = forward_retry_attempt;
+        self
+    }
+
+    /// Send the version of this crate in the [`VERSION_HEADER`] of retried requests. _Default false_.
+    ///
+    /// Lets server operators correlate retry behavior with client versions, e.g. to spot clients using a retry logic
+    /// with known bugs. The initial attempt is sent without the header.
+    pub fn with_version_header(mut self, include_version_header: bool) -> Self {
+        self.include_version_header = include_version_header;
+        self
+    }
+
+    /// Include the value of the given response header in the log event emitted for each retry.
+    ///
+    /// Useful for connecting client side retries to server side request ids (e.g. `X-Request-Id` or `CF-Ray`).
+    /// Headers missing from the response are skipped.
+    pub fn log_retry_response_header(mut self, name: impl Into<HeaderName>) -> Self {
+        self.log_retry_response_headers.push(name.into());
+        self
+    }
+
+    /// Replace the value of the given header with `[REDACTED]` in retry log events.
+    ///
+    /// `Authorization`, `Cookie`, `X-Api-Key` and `Set-Cookie` are redacted by default.
+    pub fn redact_header(mut self, name: impl Into<HeaderName>) -> Self {
+        let name = name.into();
+        if !self.redact_headers.contains(&name) {
+            self.redact_headers.push(name);
+        }
+        self
+    }
+
+    /// Log the value of the given header in retry log events, even if it is redacted by default.
+    pub fn do_not_redact_header(mut self, name: impl Into<HeaderName>) -> Self {
+        let name = name.into();
+        self.redact_headers.retain(|redacted| *redacted != name);
+        self
+    }
+
+    /// The waits before retries 1 through `attempts`, computed without sending any request.
+    ///
+    /// Covers the policy, fallback interval, fast first retry and quantization. Waits depending on a response, like
+    /// `Retry-After` or a custom wait computation, and adjustments based on previous requests are not included.
+    pub fn backoff_schedule(&self, attempts: u32) -> Vec<Duration> {
+        (1..=attempts)
+            .map(|retries| {
+                let wait = if self.fast_first_retry && retries == 1 {
+                    Duration::ZERO
+                } else {
+                    self.policy_wait(retries)
+                };
+                self.quantization
+                    .map_or(wait, |quantization| quantize(wait, quantization))
+            })
+            .collect()
+    }
+
+    /// The [`RetryMiddleware::backoff_schedule`] formatted as a table of waits and the total time spent waiting,
+    /// rounded to milliseconds.
+    ///
+    /// ```text
+    /// attempt |         wait |        total
+    ///       1 |           1s |           1s
+    /// ```
+    pub fn fmt_backoff_schedule(&self, attempts: u32) -> String {
+        let mut table = format!("{:>7} | {:>12} | {:>12}\n", "attempt", "wait", "total");
+        let mut total = Duration::ZERO;
+        for (attempt, wait) in (1..).zip(self.backoff_schedule(attempts)) {
+            total = total.saturating_add(wait);
+            table.push_str(&format!(
+                "{:>7} | {:>12} | {:>12}\n",
+                attempt,
+                format!("{:?}", round_to_millis(wait)),
+                format!("{:?}", round_to_millis(total)),
+            ));
+        }
+        table
+    }
+
+    fn log_retry(
+        &self,
+        req: &Request,
+        res: &Result<Response>,
+        retries: u32,
+        max_retries: u32,
+        wait: Duration,
+    ) {
+        let cause = match res {
+            Ok(res) => {
+                let mut cause = format!("response status {}", res.status());
+                for name in &self.log_retry_response_headers {
+                    if let Some(values) = res.header(name) {
+                        cause.push_str(&format!(" {}", self.fmt_header(name, values)));
+                    }
+                }
+                cause
+            }
+            Err(e) => format!("error {}", e),
+        };
+        log::debug!(
+            "retrying {} {} in {:?} (attempt {}/{}), {}",
+            req.method(),
+            req.url(),
+            wait,
+            retries,
+            max_retries,
+            cause
+        );
+        if log::log_enabled!(log::Level::Trace) {
+            let headers: Vec<String> = req
+                .iter()
+                .map(|(name, values)| self.fmt_header(name, values))
+                .collect();
+            log::trace!(
+                "retried request {} {} headers: {}",
+                req.method(),
+                req.url(),
+                headers.join(" ")
+            );
+        }
+    }
+
+    fn fmt_header(&self, name: &HeaderName, values: &HeaderValues) -> String {
+        if self.redact_headers.contains(name) {
+            format!("{}=[REDACTED]", name)
+        } else {
+            format!("{}={}", name, values)
+        }
+    }
+
+    fn use_policy(&self, retry_count: u32) -> Duration {
+        let wait = self.cooled_wait(self.policy_wait(retry_count));
+        match self.consecutive_failure_threshold {
+            Some(threshold) if self.consecutive_failures.load(Ordering::Relaxed) >= threshold => {
+                scale(wait, self.consecutive_failure_multiplier)
+            }
+            _ => wait,
+        }
+    }
+
+    fn cooled_wait(&self, wait: Duration) -> Duration {
+        let cooling_period = match self.cooling_period {
+            Some(cooling_period) => cooling_period,
+            None => return wait,
+        };
+        let now = self.clock.system_now();
+        let mut last_failure = self.last_failure.lock().unwrap();
+        let wait = match *last_failure {
+            Some((at, previous))
+                if now
+                    .duration_since(at)
+                    .map_or(true, |since| since < cooling_period) =>
+            {
+                wait.max(previous)
+            }
+            _ => wait,
+        };
+        *last_failure = Some((now, wait));
+        wait
+    }
+
+    fn record_outcome(&self, res: &Result<Response>, retry: bool) {
+        if self.consecutive_failure_threshold.is_none() {
+            return;
+        }
+        if retry {
+            self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+        } else if res.is_ok() {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
         }
     }
 
-    fn use_policy(&self, retry_count: u32) -> u64 {
-        let should_retry = self.policy.should_retry(retry_count);
+    /// The policy's wait before retry number `retries`, starting at 1 for the first retry.
+    fn policy_wait(&self, retries: u32) -> Duration {
+        let n_past_retries = retries.saturating_sub(1);
+        let should_retry = match self.refreshed_policy() {
+            Some(policy) => policy.should_retry(n_past_retries),
+            None => self.policy.should_retry(n_past_retries),
+        };
         if let retry_policies::RetryDecision::Retry { execute_after } = should_retry {
             match (execute_after - Utc::now()).to_std() {
-                Ok(duration) => duration.as_secs(),
-                Err(_) => self.fallback_interval,
+                Ok(duration) => duration,
+                Err(_) => Duration::from_secs(self.fallback_interval),
             }
         } else {
-            self.fallback_interval
+            Duration::from_secs(self.fallback_interval)
         }
     }
-}
 
-const RETRY_CODES: &[StatusCode] = &[StatusCode::TooManyRequests, StatusCode::RequestTimeout];
+    fn refreshed_policy(&self) -> Option<SharedPolicy> {
+        let (refresh, refresh_interval) = match (&self.policy_refresh, self.refresh_interval) {
+            (Some(refresh), Some(refresh_interval)) => (refresh, refresh_interval),
+            _ => return None,
+        };
+        let cached = self.refreshed_policy.read().unwrap().clone();
+        match cached {
+            Some((policy, loaded)) if loaded.elapsed() <= refresh_interval => Some(policy),
+            // Only one request refreshes, the others keep using the previous policy meanwhile
+            stale => match self.policy_refreshing.try_lock() {
+                Ok(_refreshing) => {
+                    let policy: SharedPolicy = Arc::from(refresh());
+                    *self.refreshed_policy.write().unwrap() =
+                        Some((policy.clone(), Instant::now()));
+                    Some(policy)
+                }
+                Err(_) => stale.map(|(policy, _)| policy),
+            },
+        }
+    }
 
-fn retry_to_seconds(header: &headers::HeaderValue) -> Result<u64> {
-    let mut secs = match header.as_str().parse::<u64>() {
-        Ok(s) => s,
-        Err(_) => {
-            let date = parse_http_date(header.as_str())?;
-            let sys_time = SystemTime::now();
-            let difference = date.duration_since(sys_time)?;
-            difference.as_secs()
+    fn escalated_max_retries(&self, max_retries: u32, age: Duration) -> u32 {
+        match &self.age_based_escalation {
+            Some(escalation) => max_retries.saturating_add(escalation(age)),
+            None => max_retries,
         }
-    };
-    if secs < 1 {
-        secs = 1;
     }
-    Ok(secs)
-}
 
-#[surf::utils::async_trait]
-impl<T: RetryPolicy + Send + Sync + 'static> Middleware for RetryMiddleware<T> {
-    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> Result<Response> {
-        let mut retries: u32 = 0;
+    /// Whether the request is never retried, regardless of its response and any escalation.
+    fn is_excluded_from_retries(&self, req: &Request) -> bool {
+        (!self.retry_upgrade_requests && is_upgrade_request(req))
+            || (self.skip_retry_for_localhost && is_localhost(req))
+    }
 
-        let r: Request = req.clone();
-        let res = next.run(r, client.clone()).await?;
-        if RETRY_CODES.contains(&res.status()) {
-            while retries < self.max_retries {
-                retries += 1;
-
-                let secs: u64;
-                if let Some(retry_after) = res.header(headers::RETRY_AFTER) {
-                    match retry_to_seconds(retry_after) {
-                        Ok(s) => {
-                            secs = s;
-                        }
-                        Err(_e) => {
-                            secs = self.use_policy(retries);
-                        }
-                    };
-                } else {
-                    secs = self.use_policy(retries);
-                };
+    fn max_retries_for(&self, req: &Request) -> u32 {
+        if self.is_excluded_from_retries(req) {
+            return 0;
+        }
+        let path = req.url().path();
+        self.endpoint_overrides
+            .iter()
+            .filter(|((method, prefix), _)| {
+                *method == req.method() && path.starts_with(prefix.as_str())
+            })
+            .max_by_key(|((_, prefix), _)| prefix.len())
+            .map_or(self.max_retries, |(_, max_retries)| *max_retries)
+    }
 
-                #[cfg(all(feature = "async-std", feature = "tokio"))]
-                compile_error!("feature \"async-std\" and feature \"tokio\" cannot be enabled at the same time");
+    fn attempt_request(&self, req: &Request, attempt: u32) -> Request {
+        let mut r: Request = req.clone();
+        if self.forward_retry_attempt && attempt > 0 {
+            r.insert_header(RETRY_ATTEMPT_HEADER, attempt.to_string());
+        }
+        if self.include_version_header && attempt > 0 {
+            r.insert_header(VERSION_HEADER, env!("CARGO_PKG_VERSION"));
+        }
+        let modifier = self
+            .per_attempt_modifiers
+            .get(attempt as usize)
+            .or_else(|| self.per_attempt_modifiers.last());
+        if let Some(modifier) = modifier {
+            modifier(&mut r);
+        }
+        if let Some((header, generate)) = &self.per_attempt_id_header {
+            r.set_header(*header, generate());
+        }
+        if let (true, Some(sign)) = (attempt > 0, &self.signing_hook) {
+            sign(&mut r, self.clock.now_utc());
+        }
+        r
+    }
 
-                #[cfg(all(feature = "async-std", feature = "wasm"))]
-                compile_error!("feature \"async-std\" and feature \"tokio\" cannot be enabled at the same time");
+    async fn run_attempt(
+        &self,
+        req: &Request,
+        attempt: u32,
+        client: Client,
+        next: Next<'_>,
+    ) -> Result<Response> {
+        let res = self.send_attempt(req, attempt, client, next);
+        match self.attempt_timeout(attempt) {
+            Some(timeout) => with_timeout(timeout, res).await,
+            None => res.await,
+        }
+    }
 
-                #[cfg(feature = "async-std")]
-                async_std::task::sleep(Duration::from_secs(secs)).await;
+    fn attempt_timeout(&self, attempt: u32) -> Option<Duration> {
+        let timeout = self.per_attempt_timeout?;
+        let timeout = match self.timeout_escalation_factor {
+            Some(factor) => scale(
+                timeout,
+                factor.powi(i32::try_from(attempt).unwrap_or(i32::MAX)),
+            ),
+            None => timeout,
+        };
+        Some(
+            self.max_per_attempt_timeout
+                .map_or(timeout, |max| timeout.min(max)),
+        )
+    }
 
-                #[cfg(any(feature = "tokio", feature = "wasm"))]
-                tokio::time::sleep(Duration::from_secs(secs)).await;
+    async fn send_attempt(
+        &self,
+        req: &Request,
+        attempt: u32,
+        client: Client,
+        next: Next<'_>,
+    ) -> Result<Response> {
+        let r = self.attempt_request(req, attempt);
+        #[cfg(feature = "deduplication")]
+        if let Some(cache) = self.deduplication_cache.as_ref().filter(|_| attempt > 0) {
+            return deduplication::run(cache, r, client, next).await;
+        }
+        next.run(r, client).await
+    }
 
-                let r: Request = req.clone();
-                let res = next.run(r, client.clone()).await?;
-                if !RETRY_CODES.contains(&res.status()) {
-                    return Ok(res);
-                }
+    async fn needs_retry(&self, res: &mut Response) -> Result<bool> {
+        if self.retry_codes.contains(&res.status()) {
+            return Ok(true);
+        }
+        if let (true, Some(validator)) = (res.status().is_success(), &self.response_validator) {
+            if !validator(res) {
+                return Ok(true);
             }
         }
-        Ok(res)
+        if self.retry_body_patterns.is_empty() {
+            return Ok(false);
+        }
+        let matches = |prefix: &[u8]| {
+            self.retry_body_patterns.iter().any(|pattern| {
+                prefix
+                    .windows(pattern.len())
+                    .any(|window| window == pattern.as_bytes())
+            })
+        };
+        let prefix = peek_body(res, self.max_response_body_for_predicate, matches).await?;
+        Ok(matches(&prefix))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::*;
-    use surf::{http::Method, Client, Request};
-    use surf_governor::GovernorMiddleware;
-    use url::Url;
-    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+    async fn sse_reconnect_delay(&self, res: &mut Result<Response>) -> Result<Option<Duration>> {
+        let res = match res {
+            Ok(res) if self.sse_retry_header => res,
+            _ => return Ok(None),
+        };
+        let is_event_stream = res
+            .content_type()
+            .map_or(false, |mime| mime.essence() == "text/event-stream");
+        if !is_event_stream {
+            return Ok(None);
+        }
+        let prefix = peek_body(
+            res,
+            self.max_response_body_for_predicate,
+            ends_first_sse_retry_or_event,
+        )
+        .await?;
+        Ok(parse_sse_retry(&String::from_utf8_lossy(&prefix)))
+    }
 
-    #[async_std::test]
-    async fn will_retry_request() -> Result<()> {
-        let mock_server = MockServer::start().await;
-        let m = Mock::given(method("GET"))
-            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
-            .expect(2);
-        let _mock_guard = mock_server.register_as_scoped(m).await;
-        let url = format!("{}/", &mock_server.uri());
-        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
-        let retry = RetryMiddleware::default();
-        let client = Client::new()
-            .with(retry)
-            .with(GovernorMiddleware::per_second(1)?);
-        let good_res = client.send(req.clone()).await?;
-        assert_eq!(good_res.status(), 200);
-        let wait_res = client.send(req).await?;
-        assert_eq!(wait_res.status(), 200);
-        Ok(())
+    fn is_retryable_error(&self, err: &surf::Error) -> bool {
+        (self.drain_timeout.is_some() && is_connection_reset(err))
+            || err.downcast_ref::<AttemptTimedOut>().is_some()
+            || (self.retry_on_dns_error && is_dns_error(err))
+            || (self.retry_on_goaway && is_goaway(err))
+    }
+
+    fn retry_wait(&self, res: &Result<Response>, retries: u32) -> Duration {
+        if self.fast_first_retry && retries == 1 {
+            return Duration::ZERO;
+        }
+        let res = match res {
+            Ok(res) => res,
+            Err(e) if self.retry_on_goaway && is_goaway(e) => return Duration::ZERO,
+            Err(_) => return self.use_policy(retries),
+        };
+        let mut wait = self.computed_wait(res, retries);
+        if self.delay_multiplier_from_header {
+            if let Some(multiplier) = res
+                .header(BACKOFF_MULTIPLIER_HEADER)
+                .and_then(parse_backoff_multiplier)
+            {
+                wait = scale(wait, multiplier.min(self.max_delay_multiplier));
+            }
+        }
+        wait
+    }
+
+    fn rate_limit_pause(&self, res: &Response) -> Option<Duration> {
+        let threshold = self.rate_limit_remaining_threshold?;
+        if !res.status().is_success() {
+            return None;
+        }
+        let remaining: u32 = res
+            .header(RATE_LIMIT_REMAINING_HEADER)?
+            .as_str()
+            .trim()
+            .parse()
+            .ok()?;
+        if remaining > threshold {
+            return None;
+        }
+        let reset = res
+            .header(RATE_LIMIT_RESET_HEADER)
+            .and_then(|value| value.as_str().trim().parse::<u64>().ok());
+        let pause = match reset {
+            Some(reset) => rate_limit_reset_wait(reset, self.clock.system_now()),
+            None => self.policy_wait(1),
+        };
+        Some(pause.min(self.max_rate_limit_pause))
+    }
+
+    #[cfg(feature = "stale-cache")]
+    fn stale_response(&self, req: &Request) -> Option<Response> {
+        let cache = self.stale_cache.as_ref()?;
+        if !matches!(req.method(), Method::Get | Method::Head | Method::Options) {
+            return None;
+        }
+        let mut res = cache(req)?;
+        res.insert_header(STALE_HEADER, "true");
+        log::warn!("serving stale response for {} {}", req.method(), req.url());
+        Some(res)
+    }
+
+    fn check_cancellation(&self) -> Result<()> {
+        match &self.cancellation_token {
+            Some(token) if token.load(Ordering::Relaxed) => {
+                Err(surf::Error::new(StatusCode::BadRequest, RetryCancelled))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sleep for `wait`, returning early once the cancellation token is set.
+    async fn sleep_unless_cancelled(&self, wait: Duration) {
+        let token = match &self.cancellation_token {
+            Some(token) => token,
+            None => return sleep(wait).await,
+        };
+        let cancelled = async {
+            while !token.load(Ordering::Relaxed) {
+                sleep(CANCELLATION_POLL_INTERVAL).await;
+            }
+        };
+        futures_lite::future::or(sleep(wait), cancelled).await;
+    }
+
+    /// Whether `retry_wait` takes the wait from the policy rather than the server or a custom
+    /// wait computation.
+    fn is_policy_wait(&self, res: &Result<Response>) -> bool {
+        match res {
+            Ok(res) => {
+                self.custom_wait_fn.is_none()
+                    && res
+                        .header(headers::RETRY_AFTER)
+                        .map_or(true, |retry_after| {
+                            retry_to_seconds(retry_after, self.clock.system_now()).is_err()
+                        })
+                    && self.server_timing_wait(res).is_none()
+            }
+            Err(e) => !(self.retry_on_goaway && is_goaway(e)),
+        }
+    }
+
+    fn queue_adjusted_wait(&self, wait: Duration, policy_wait: bool) -> Duration {
+        let (queue_depth, max_queue_depth) = match (&self.queue_depth_fn, self.max_queue_depth) {
+            (Some(queue_depth), Some(max_queue_depth)) => (queue_depth(), max_queue_depth),
+            _ => return wait,
+        };
+        if queue_depth > max_queue_depth {
+            scale(wait, self.queue_depth_multiplier)
+        } else if queue_depth == 0 && policy_wait {
+            wait.min(self.policy_wait(1))
+        } else {
+            wait
+        }
+    }
+
+    fn computed_wait(&self, res: &Response, retries: u32) -> Duration {
+        if let Some(wait_fn) = &self.custom_wait_fn {
+            return wait_fn(res, retries);
+        }
+        if let Some(retry_after) = res.header(headers::RETRY_AFTER) {
+            match retry_to_seconds(retry_after, self.clock.system_now()) {
+                Ok(secs) => Duration::from_secs(secs),
+                Err(_e) => self.use_policy(retries),
+            }
+        } else if let Some(wait) = self.server_timing_wait(res) {
+            wait
+        } else {
+            self.use_policy(retries)
+        }
+    }
+
+    fn server_timing_wait(&self, res: &Response) -> Option<Duration> {
+        if !self.parse_server_timing {
+            return None;
+        }
+        res.header("Server-Timing")?
+            .iter()
+            .find_map(parse_server_timing_wait)
+    }
+}
+
+#[cfg(feature = "typemap")]
+#[cfg_attr(docsrs, doc(cfg(feature = "typemap")))]
+impl<T: RetryPolicy + Send + Sync + 'static> RetryMiddleware<T> {
+    /// Box the middleware for storage in a `TypeMap` style state store, as used by frameworks like Tide or Actix.
+    pub fn into_type_map_value(self) -> Box<dyn Any + Send + Sync> {
+        Box::new(self)
+    }
+
+    /// Retrieve a middleware previously stored with [`RetryMiddleware::into_type_map_value`].
+    ///
+    /// Returns `None` if the value is not a `RetryMiddleware` with this policy type.
+    // Taking the box itself prevents `&Box<dyn Any>` from being unsized into a `&dyn Any` of the box.
+    #[allow(clippy::borrowed_box)]
+    pub fn from_type_map_value(val: &Box<dyn Any + Send + Sync>) -> Option<&Self> {
+        (**val).downcast_ref::<Self>()
+    }
+}
+
+#[cfg(feature = "serde")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+impl RetryMiddleware<SerdePolicy> {
+    /// Construct the retry middleware with a [`SerdePolicy`], keeping a clone of the policy to update it at runtime.
+    ///
+    /// # Example
+    /// ```
+    /// use surf_retry::{RetryMiddleware, SerdePolicy};
+    ///
+    /// # fn run() -> serde_json::Result<()> {
+    /// let policy = SerdePolicy::new(r#"{"initial_ms": 500, "max_interval_ms": 30000}"#)?;
+    /// let retry = RetryMiddleware::with_serde_policy(3, policy.clone(), 1);
+    /// // Later, e.g. when the configuration pushed by the server changes
+    /// policy.update(r#"{"initial_ms": 2000, "max_interval_ms": 60000}"#)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_serde_policy(
+        max_retries: u32,
+        policy: SerdePolicy,
+        fallback_interval: u64,
+    ) -> Self {
+        Self::new(max_retries, policy, fallback_interval)
+    }
+}
+
+/// Header carrying the retry attempt number to middleware later in the chain, see [`RetryMiddleware::with_header_forwarding_to_next`].
+pub const RETRY_ATTEMPT_HEADER: &str = "X-Surf-Retry-Attempt";
+
+/// Header carrying the version of this crate on retried requests, see [`RetryMiddleware::with_version_header`].
+pub const VERSION_HEADER: &str = "X-Surf-Retry-Version";
+
+/// The retry attempt number of a request, stored in the request extensions by [`RetryInfoExtractor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryAttempt(pub u32);
+
+/// Information about the retries made for a request, stored in the extensions of the final response.
+///
+/// Only present if the request was retried and headers to extract were configured with
+/// [`RetryMiddleware::extract_response_header`] or [`RetryMiddleware::extract_retry_after`].
+#[derive(Default)]
+pub struct RetryContext {
+    retries: u32,
+    values: HashMap<(HeaderName, TypeId), AnyValue>,
+}
+
+impl RetryContext {
+    /// The number of retries made.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// The latest extracted value of the header, if it was extracted as type `V`.
+    ///
+    /// A header extracted as several types keeps a value for each of them.
+    pub fn get<V: 'static>(&self, name: impl Into<HeaderName>) -> Option<&V> {
+        self.values
+            .get(&(name.into(), TypeId::of::<V>()))?
+            .downcast_ref()
+    }
+
+    fn record(&mut self, extractors: &[HeaderExtractor], res: &Response, now: SystemTime) {
+        for extractor in extractors {
+            if let Some(value) = res
+                .header(&extractor.name)
+                .and_then(|values| (extractor.parse)(values, now))
+            {
+                self.values
+                    .insert((extractor.name.clone(), extractor.type_id), value);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for RetryContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryContext")
+            .field("retries", &self.retries)
+            .field(
+                "values",
+                &self.values.keys().map(|(name, _)| name).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// Middleware that strips the [`RETRY_ATTEMPT_HEADER`] from a request and stores its value as a [`RetryAttempt`] extension.
+///
+/// Requests without the header are passed on unchanged.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RetryInfoExtractor;
+
+#[surf::utils::async_trait]
+impl Middleware for RetryInfoExtractor {
+    async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> Result<Response> {
+        if let Some(values) = req.remove_header(RETRY_ATTEMPT_HEADER) {
+            if let Ok(attempt) = values.as_str().parse::<u32>() {
+                req.set_ext(RetryAttempt(attempt));
+            }
+        }
+        next.run(req, client).await
+    }
+}
+
+/// Error returned once the retries of a request are stopped by the
+/// [cancellation token](RetryMiddleware::with_cancellation_token).
+///
+/// It carries a `400 Bad Request` status, as the request was cancelled by the client and not failed by the server.
+/// Downcast the [`surf::Error`] to tell it apart from other errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryCancelled;
+
+impl fmt::Display for RetryCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("retry cancelled")
+    }
+}
+
+impl std::error::Error for RetryCancelled {}
+
+/// Longest delay between setting the cancellation token and the end of a retry wait, see
+/// [`RetryMiddleware::with_cancellation_token`].
+pub const CANCELLATION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+const RETRY_CODES: &[StatusCode] = &[StatusCode::TooManyRequests, StatusCode::RequestTimeout];
+
+/// Header marking a stale response served from the cache, see
+/// [`RetryMiddleware::with_response_cache_on_exhaustion`].
+#[cfg(feature = "stale-cache")]
+#[cfg_attr(docsrs, doc(cfg(feature = "stale-cache")))]
+pub const STALE_HEADER: &str = "X-Stale";
+
+/// Header with the number of requests left in the current rate limit window, see
+/// [`RetryMiddleware::with_rate_limit_remaining_threshold`].
+pub const RATE_LIMIT_REMAINING_HEADER: &str = "X-RateLimit-Remaining";
+
+/// Header with the reset time of the current rate limit window, see
+/// [`RetryMiddleware::with_rate_limit_remaining_threshold`].
+pub const RATE_LIMIT_RESET_HEADER: &str = "X-RateLimit-Reset";
+
+/// The wait until a rate limit reset given in seconds or, for values past 2001, as a Unix timestamp.
+fn rate_limit_reset_wait(reset: u64, now: SystemTime) -> Duration {
+    const TIMESTAMP_THRESHOLD: u64 = 1_000_000_000;
+    if reset < TIMESTAMP_THRESHOLD {
+        return Duration::from_secs(reset);
+    }
+    (SystemTime::UNIX_EPOCH + Duration::from_secs(reset))
+        .duration_since(now)
+        .unwrap_or_default()
+}
+
+/// Non-standard header some API gateways use to announce the maximum number of retries they accept, see
+/// [`RetryMiddleware::with_attempt_budget_from_server`].
+pub const MAX_CLIENT_RETRIES_HEADER: &str = "X-Max-Client-Retries";
+
+/// Non-standard header some servers use to suggest a multiplier for the computed wait, see
+/// [`RetryMiddleware::with_delay_multiplier_from_header`].
+pub const BACKOFF_MULTIPLIER_HEADER: &str = "X-Backoff-Multiplier";
+
+fn parse_backoff_multiplier(header: &HeaderValues) -> Option<f64> {
+    header
+        .as_str()
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|multiplier| multiplier.is_finite() && *multiplier > 0.0)
+}
+
+/// Multiply a duration by a non-negative factor, saturating instead of panicking on overflow.
+fn scale(duration: Duration, factor: f64) -> Duration {
+    let secs = duration.as_secs_f64() * factor;
+    if secs >= Duration::MAX.as_secs_f64() {
+        Duration::MAX
+    } else {
+        Duration::from_secs_f64(secs.max(0.0))
+    }
+}
+
+/// The duration of the `wait` metric in a `Server-Timing` header value, e.g. `wait;dur=1500` or
+/// `db;dur=53, wait;desc="Retry";dur=1500`.
+fn parse_server_timing_wait(header: &headers::HeaderValue) -> Option<Duration> {
+    header.as_str().split(',').find_map(|metric| {
+        let mut params = metric.split(';').map(str::trim);
+        if !params.next()?.eq_ignore_ascii_case("wait") {
+            return None;
+        }
+        params
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("dur"))
+            .and_then(|(_, dur)| dur.trim().parse::<f64>().ok())
+            .filter(|millis| millis.is_finite() && *millis >= 0.0)
+            .map(|millis| scale(Duration::from_millis(1), millis))
+    })
+}
+
+/// Read up to `limit` bytes of the response body, leaving the complete body in place.
+async fn peek_body(
+    res: &mut Response,
+    limit: usize,
+    complete: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>> {
+    let mut body = res.take_body();
+    let len = body.len();
+    let mime = body.mime().clone();
+    let mut prefix = Vec::new();
+    let mut chunk = [0; 1024];
+    // Streaming bodies may stay open indefinitely, stop reading as soon as the prefix is complete
+    while prefix.len() < limit && !complete(&prefix) {
+        let max = chunk.len().min(limit - prefix.len());
+        let read = body.read(&mut chunk[..max]).await?;
+        if read == 0 {
+            break;
+        }
+        prefix.extend_from_slice(&chunk[..read]);
+    }
+    let mut body = Body::from_reader(Cursor::new(prefix.clone()).chain(body), len);
+    body.set_mime(mime);
+    res.set_body(body);
+    Ok(prefix)
+}
+
+/// Whether a Server-Sent Events stream prefix contains a complete `retry:` field or the blank line ending an event.
+fn ends_first_sse_retry_or_event(prefix: &[u8]) -> bool {
+    let complete_lines = match prefix.iter().rposition(|b| *b == b'\n') {
+        Some(end) => &prefix[..end],
+        None => return false,
+    };
+    complete_lines
+        .split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .any(|line| line.is_empty() || line.starts_with(b"retry:"))
+}
+
+/// The reconnection time of the last valid `retry:` field in a Server-Sent Events stream.
+fn parse_sse_retry(stream: &str) -> Option<Duration> {
+    stream
+        .lines()
+        .filter_map(|line| line.strip_prefix("retry:"))
+        .filter_map(|value| {
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            value.parse().ok().map(Duration::from_millis)
+        })
+        .next_back()
+}
+
+/// Round `wait` up to the next multiple of `quantization`, saturating at [`Duration::MAX`].
+fn quantize(wait: Duration, quantization: Duration) -> Duration {
+    let step = quantization.as_nanos();
+    if step == 0 {
+        return wait;
+    }
+    let nanos = (wait.as_nanos() + step - 1) / step * step;
+    u64::try_from(nanos / 1_000_000_000).map_or(Duration::MAX, |secs| {
+        Duration::new(secs, (nanos % 1_000_000_000) as u32)
+    })
+}
+
+/// Extend `wait` to the next multiple of `window` since the epoch if the retry would happen at most `tolerance`
+/// before it.
+fn align_to_window(
+    now: Duration,
+    wait: Duration,
+    window: Duration,
+    tolerance: Duration,
+) -> Duration {
+    let retry_at = match now.checked_add(wait) {
+        Some(retry_at) => retry_at,
+        None => return wait,
+    };
+    let boundary = quantize(retry_at, window);
+    if boundary - retry_at <= tolerance {
+        boundary - now
+    } else {
+        wait
+    }
+}
+
+fn round_to_millis(duration: Duration) -> Duration {
+    duration
+        .checked_add(Duration::from_micros(500))
+        .map_or(duration, |d| {
+            Duration::from_millis(u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+        })
+}
+
+/// Add the cookies set by the response to the request's `Cookie` header.
+fn merge_set_cookies(req: &mut Request, res: &Response) {
+    let set_cookies = match res.header(headers::SET_COOKIE) {
+        Some(values) => values,
+        None => return,
+    };
+    let mut cookies: Vec<(String, String)> = req
+        .header(headers::COOKIE)
+        .map(|values| {
+            values
+                .iter()
+                .flat_map(|value| value.as_str().split(';'))
+                .filter_map(parse_cookie_pair)
+                .collect()
+        })
+        .unwrap_or_default();
+    for set_cookie in set_cookies {
+        let pair = set_cookie
+            .as_str()
+            .split(';')
+            .next()
+            .and_then(parse_cookie_pair);
+        if let Some((name, value)) = pair {
+            match cookies.iter_mut().find(|(existing, _)| *existing == name) {
+                Some(cookie) => cookie.1 = value,
+                None => cookies.push((name, value)),
+            }
+        }
+    }
+    let cookie = cookies
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("; ");
+    req.insert_header(headers::COOKIE, cookie);
+}
+
+fn parse_cookie_pair(pair: &str) -> Option<(String, String)> {
+    let (name, value) = pair.trim().split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((name.to_owned(), value.trim().to_owned()))
+}
+
+fn is_localhost(req: &Request) -> bool {
+    matches!(req.url().host_str(), Some("localhost") | Some("127.0.0.1"))
+}
+
+/// Whether the request asks for a protocol upgrade.
+fn is_upgrade_request(req: &Request) -> bool {
+    req.header(headers::CONNECTION).map_or(false, |values| {
+        values.iter().any(|value| {
+            value
+                .as_str()
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        })
+    })
+}
+
+/// Error of an attempt aborted by the per attempt timeout.
+#[derive(Debug)]
+struct AttemptTimedOut(Duration);
+
+impl fmt::Display for AttemptTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "attempt timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for AttemptTimedOut {}
+
+#[cfg(all(feature = "async-std", feature = "tokio"))]
+compile_error!("feature \"async-std\" and feature \"tokio\" cannot be enabled at the same time");
+
+#[cfg(all(feature = "async-std", feature = "wasm"))]
+compile_error!("feature \"async-std\" and feature \"tokio\" cannot be enabled at the same time");
+
+async fn sleep(wait: Duration) {
+    #[cfg(feature = "async-std")]
+    async_std::task::sleep(wait).await;
+
+    #[cfg(any(feature = "tokio", feature = "wasm"))]
+    tokio::time::sleep(wait).await;
+
+    // Without a runtime there is no timer, retries are made immediately
+    #[cfg(not(any(feature = "async-std", feature = "tokio", feature = "wasm")))]
+    let _ = wait;
+}
+
+async fn with_timeout(
+    timeout: Duration,
+    attempt: impl Future<Output = Result<Response>>,
+) -> Result<Response> {
+    #[cfg(feature = "async-std")]
+    let res = async_std::future::timeout(timeout, attempt).await;
+
+    #[cfg(any(feature = "tokio", feature = "wasm"))]
+    let res = tokio::time::timeout(timeout, attempt).await;
+
+    // Without a runtime there is no timer to race the attempt against
+    #[cfg(not(any(feature = "async-std", feature = "tokio", feature = "wasm")))]
+    let res: std::result::Result<_, std::convert::Infallible> = {
+        let _ = timeout;
+        Ok(attempt.await)
+    };
+
+    res.unwrap_or_else(|_| {
+        Err(surf::Error::new(
+            StatusCode::RequestTimeout,
+            AttemptTimedOut(timeout),
+        ))
+    })
+}
+
+/// Iterate over an error and its sources.
+fn error_chain(err: &surf::Error) -> impl Iterator<Item = &(dyn std::error::Error + 'static)> {
+    let root: &(dyn std::error::Error + 'static) =
+        AsRef::<dyn std::error::Error + Send + Sync>::as_ref(err);
+    std::iter::successors(Some(root), |e| e.source())
+}
+
+/// Whether the error indicates the server closed an existing connection.
+fn is_connection_reset(err: &surf::Error) -> bool {
+    error_chain(err).any(|e| {
+        e.downcast_ref::<std::io::Error>().map_or(false, |e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+            )
+        })
+    })
+}
+
+/// Whether the error indicates a failure to resolve the host name, see
+/// [`RetryMiddleware::with_retry_on_dns_error`].
+fn is_dns_error(err: &surf::Error) -> bool {
+    const DNS_ERROR_MESSAGES: &[&str] = &[
+        "failed to lookup address information",
+        "resolve host",
+        "dns error",
+        "no record found",
+        "name or service not known",
+    ];
+    error_chain(err).any(|e| {
+        let message = e.to_string().to_ascii_lowercase();
+        DNS_ERROR_MESSAGES
+            .iter()
+            .any(|dns_message| message.contains(dns_message))
+    })
+}
+
+/// Whether the error indicates the request was cancelled by an HTTP/2 `GOAWAY` frame before it was processed, see
+/// [`RetryMiddleware::with_http2_goaway_retry`].
+fn is_goaway(err: &surf::Error) -> bool {
+    const GOAWAY_MESSAGES: &[&str] = &["goaway", "refused_stream", "refused stream"];
+    error_chain(err).any(|e| {
+        let message = e.to_string().to_ascii_lowercase();
+        GOAWAY_MESSAGES
+            .iter()
+            .any(|goaway_message| message.contains(goaway_message))
+    })
+}
+
+fn retry_to_seconds(header: &headers::HeaderValue, now: SystemTime) -> Result<u64> {
+    let mut secs = match header.as_str().parse::<u64>() {
+        Ok(s) => s,
+        Err(_) => {
+            let date = parse_http_date(header.as_str())?;
+            let difference = date.duration_since(now)?;
+            difference.as_secs()
+        }
+    };
+    if secs < 1 {
+        secs = 1;
+    }
+    Ok(secs)
+}
+
+#[surf::utils::async_trait]
+impl<T: RetryPolicy + Send + Sync + 'static> Middleware for RetryMiddleware<T> {
+    async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> Result<Response> {
+        let max_retries = self.max_retries_for(&req);
+        // Only read the clock when escalating, `Instant::now` panics on wasm32-unknown-unknown
+        let escalate = self.age_based_escalation.is_some() && !self.is_excluded_from_retries(&req);
+        let received = escalate.then(Instant::now);
+        let mut server_budget = u32::MAX;
+        let mut budget_read = !self.attempt_budget_from_server;
+        let mut retries: u32 = 0;
+        let mut drained = false;
+        let mut context: Option<RetryContext> = None;
+
+        let mut res = self.run_attempt(&req, retries, client.clone(), next).await;
+        // Whether `res` is from a new attempt, retries skipped by the health check keep the previous one
+        let mut attempted = true;
+        // Whether the last attempt still needs a retry when the loop ends
+        let gave_up = loop {
+            let retry = match &mut res {
+                Ok(res) => self.needs_retry(res).await?,
+                Err(e) => self.is_retryable_error(e),
+            };
+            if attempted {
+                self.record_outcome(&res, retry);
+                if let Some(breaker) = &self.circuit_breaker {
+                    if retry || res.is_err() {
+                        breaker.record_failure();
+                    } else {
+                        breaker.record_success();
+                    }
+                }
+            }
+            if let (false, Ok(res)) = (budget_read, &res) {
+                if res.status() == StatusCode::TooManyRequests {
+                    budget_read = true;
+                    if let Some(budget) = res
+                        .header(MAX_CLIENT_RETRIES_HEADER)
+                        .and_then(|value| value.as_str().trim().parse::<u32>().ok())
+                    {
+                        server_budget = budget;
+                    }
+                }
+            }
+            let limit = match received {
+                Some(received) => self.escalated_max_retries(max_retries, received.elapsed()),
+                None => max_retries,
+            }
+            .min(server_budget);
+            if !retry {
+                break false;
+            }
+            if retries >= limit {
+                break true;
+            }
+            if self
+                .circuit_breaker
+                .as_ref()
+                .map_or(false, |breaker| breaker.is_open())
+            {
+                break true;
+            }
+            if let Some(window) = &self.retry_window {
+                if !window(self.clock.now_utc()) {
+                    break true;
+                }
+            }
+            retries += 1;
+            self.check_cancellation()?;
+
+            let (mut wait, policy_wait) = match self.sse_reconnect_delay(&mut res).await? {
+                Some(wait) => (wait, false),
+                None => (self.retry_wait(&res, retries), self.is_policy_wait(&res)),
+            };
+            let mut draining = false;
+            if let (Some(drain_timeout), Err(e)) = (self.drain_timeout, &res) {
+                if !drained && is_connection_reset(e) {
+                    wait = wait.saturating_add(drain_timeout);
+                    draining = true;
+                }
+            }
+            wait = self.queue_adjusted_wait(wait, policy_wait);
+            if let Some(quantization) = self.quantization {
+                wait = quantize(wait, quantization);
+            }
+            if let (Some(window), Ok(now)) = (
+                self.align_to_window,
+                self.clock
+                    .system_now()
+                    .duration_since(SystemTime::UNIX_EPOCH),
+            ) {
+                wait = align_to_window(now, wait, window, self.window_alignment_tolerance);
+            }
+            if let Some(before_sleep) = &self.before_sleep {
+                wait = before_sleep(wait);
+            }
+            if self.skip_sleep_on_last_retry && retries == limit {
+                wait = Duration::ZERO;
+            }
+            self.log_retry(&req, &res, retries, limit, wait);
+            if let (true, Ok(res)) = (self.sticky_cookies, &res) {
+                merge_set_cookies(&mut req, res);
+            }
+            if let (false, Ok(res)) = (self.extract_response_headers.is_empty(), &res) {
+                context.get_or_insert_with(RetryContext::default).record(
+                    &self.extract_response_headers,
+                    res,
+                    self.clock.system_now(),
+                );
+            }
+
+            let healthy = match &self.health_check {
+                Some(health_check) => health_check(client.clone()).await,
+                None => true,
+            };
+            if !healthy {
+                log::debug!(
+                    "health check failed, skipping retry {}/{} of {} {}",
+                    retries,
+                    limit,
+                    req.method(),
+                    req.url()
+                );
+            }
+
+            if let Some(stats) = &self.backoff_stats {
+                stats.record(wait);
+            }
+            self.sleep_unless_cancelled(wait).await;
+            self.check_cancellation()?;
+            attempted = healthy;
+            if healthy {
+                drained |= draining;
+                res = self.run_attempt(&req, retries, client.clone(), next).await;
+            }
+        };
+        if gave_up {
+            log::debug!(
+                "giving up on {} {} after {} retries",
+                req.method(),
+                req.url(),
+                retries
+            );
+        }
+        #[cfg(feature = "stale-cache")]
+        if gave_up || res.is_err() {
+            if let Some(stale) = self.stale_response(&req) {
+                return Ok(stale);
+            }
+        }
+        if let Some(pause) = res.as_ref().ok().and_then(|res| self.rate_limit_pause(res)) {
+            log::debug!(
+                "rate limit almost exhausted, pausing {:?} after {} {}",
+                pause,
+                req.method(),
+                req.url()
+            );
+            sleep(pause).await;
+        }
+        if let (Some(mut context), Ok(res)) = (context, &mut res) {
+            context.retries = retries;
+            res.insert_ext(context);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use surf::{http::Method, Client, Request};
+    use surf_governor::GovernorMiddleware;
+    use url::Url;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    /// Start a mock server answering the first `failures` requests with `failure` and all later ones with 200.
+    async fn flaky_server(failures: u64, failure: ResponseTemplate) -> MockServer {
+        let mock_server = MockServer::start().await;
+        if failures > 0 {
+            Mock::given(method("GET"))
+                .respond_with(failure)
+                .up_to_n_times(failures)
+                .expect(failures)
+                .mount(&mock_server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    fn get(mock_server: &MockServer) -> Request {
+        let url = format!("{}/", &mock_server.uri());
+        Request::new(Method::Get, Url::parse(&url).unwrap())
+    }
+
+    #[async_std::test]
+    async fn will_retry_request() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = format!("{}/", &mock_server.uri());
+        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
+        let retry = RetryMiddleware::default();
+        let client = Client::new()
+            .with(retry)
+            .with(GovernorMiddleware::per_second(1)?);
+        let good_res = client.send(req.clone()).await?;
+        assert_eq!(good_res.status(), 200);
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 200);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn custom_wait_computation_replaces_policy() -> Result<()> {
+        let mock_server = flaky_server(
+            2,
+            ResponseTemplate::new(429).insert_header("Retry-After", "30"),
+        )
+        .await;
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let seen = attempts.clone();
+        let retry = RetryMiddleware::default().with_custom_wait_computation(move |res, attempt| {
+            assert_eq!(res.status(), 429);
+            seen.lock().unwrap().push(attempt);
+            Duration::from_millis(1)
+        });
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(*attempts.lock().unwrap(), vec![1, 2]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn fast_first_retry_skips_first_wait() -> Result<()> {
+        let mock_server = flaky_server(2, ResponseTemplate::new(429)).await;
+        let backoff = Duration::from_millis(300);
+        let retry = RetryMiddleware::new(
+            3,
+            TruncatedExponentialBackoff::new(backoff, 1.0, backoff),
+            1,
+        )
+        .with_fast_first_retry(true);
+        let started = Instant::now();
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        let elapsed = started.elapsed();
+        assert_eq!(res.status(), 200);
+        // only the second retry waits for the configured backoff
+        assert!(elapsed >= backoff, "{:?}", elapsed);
+        assert!(elapsed < backoff * 2, "{:?}", elapsed);
+        Ok(())
+    }
+
+    #[derive(Debug, Default, Clone)]
+    struct RecordAttempts(Arc<Mutex<Vec<Option<RetryAttempt>>>>);
+
+    #[surf::utils::async_trait]
+    impl Middleware for RecordAttempts {
+        async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> Result<Response> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(req.ext::<RetryAttempt>().copied());
+            next.run(req, client).await
+        }
+    }
+
+    #[async_std::test]
+    async fn retry_attempt_is_forwarded_to_next() -> Result<()> {
+        let mock_server = flaky_server(1, ResponseTemplate::new(429)).await;
+        let retry = RetryMiddleware::default()
+            .with_fast_first_retry(true)
+            .with_header_forwarding_to_next(true);
+        let recorder = RecordAttempts::default();
+        let client = Client::new()
+            .with(retry)
+            .with(RetryInfoExtractor)
+            .with(recorder.clone());
+        let res = client.send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![None, Some(RetryAttempt(1))]
+        );
+        let received = mock_server.received_requests().await.unwrap();
+        assert!(received.iter().all(|r| !r
+            .headers
+            .contains_key(&HeaderName::from(RETRY_ATTEMPT_HEADER))));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn version_header_is_sent_on_retries() -> Result<()> {
+        let mock_server = flaky_server(1, ResponseTemplate::new(429)).await;
+        let retry = RetryMiddleware::default()
+            .with_fast_first_retry(true)
+            .with_version_header(true);
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        let versions: Vec<Option<String>> = mock_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| {
+                r.headers
+                    .get(&HeaderName::from(VERSION_HEADER))
+                    .map(|v| v.as_str().to_string())
+            })
+            .collect();
+        assert_eq!(
+            versions,
+            vec![None, Some(env!("CARGO_PKG_VERSION").to_string())]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn per_attempt_modifiers_reuse_the_last_modifier() -> Result<()> {
+        let mock_server = flaky_server(2, ResponseTemplate::new(429)).await;
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::ZERO)
+            .add_per_attempt_modifier(|req| req.set_header("X-Token", "initial"))
+            .add_per_attempt_modifier(|req| req.set_header("X-Token", "retry"));
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        let tokens: Vec<String> = mock_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| r.headers[&HeaderName::from("X-Token")].as_str().to_string())
+            .collect();
+        assert_eq!(tokens, vec!["initial", "retry", "retry"]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn only_retry_codes_replaces_defaults() -> Result<()> {
+        let mock_server = flaky_server(1, ResponseTemplate::new(503)).await;
+        let retry = RetryMiddleware::default()
+            .with_fast_first_retry(true)
+            .only_retry_codes(vec![StatusCode::ServiceUnavailable]);
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+
+        let mock_server = flaky_server(1, ResponseTemplate::new(429)).await;
+        let retry = RetryMiddleware::default()
+            .with_fast_first_retry(true)
+            .only_retry_codes(vec![StatusCode::ServiceUnavailable]);
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn retries_on_matching_body() -> Result<()> {
+        let error = ResponseTemplate::new(200).set_body_string("error: rate_limit_exceeded");
+        let retry = || {
+            RetryMiddleware::default()
+                .with_fast_first_retry(true)
+                .retry_if_body_contains("rate_limit_exceeded")
+        };
+
+        let mock_server = flaky_server(1, error.clone()).await;
+        let res = Client::new().with(retry()).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+
+        // the pattern lies beyond the searched prefix, the full body is passed on
+        let mock_server = flaky_server(1, error).await;
+        let client = Client::new().with(retry().max_response_body_for_predicate(8));
+        let body = client.recv_string(get(&mock_server)).await?;
+        assert_eq!(body, "error: rate_limit_exceeded");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn empty_body_patterns_are_ignored() -> Result<()> {
+        let retry = RetryMiddleware::default().retry_if_body_contains("");
+        assert!(retry.retry_body_patterns.is_empty());
+        let mut res = Response::from(surf::http::Response::new(StatusCode::Ok));
+        assert!(!retry.needs_retry(&mut res).await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn body_pattern_does_not_wait_for_the_stream_to_end() -> Result<()> {
+        let retry = RetryMiddleware::default().retry_if_body_contains("rate_limit_exceeded");
+        let mut res = open_stream("error: rate_limit_exceeded", "text/plain");
+        let matched =
+            async_std::future::timeout(Duration::from_secs(1), retry.needs_retry(&mut res)).await?;
+        assert!(matched?);
+        Ok(())
+    }
+
+    fn response_with_header(name: &str, value: &str) -> Response {
+        let mut res = surf::http::Response::new(StatusCode::TooManyRequests);
+        res.insert_header(name, value);
+        res.into()
+    }
+
+    #[test]
+    fn low_rate_limit_remaining_pauses() {
+        let interval = Duration::from_millis(100);
+        let retry = RetryMiddleware::new(
+            3,
+            TruncatedExponentialBackoff::new(interval, 1.0, interval),
+            0,
+        )
+        .with_rate_limit_remaining_threshold(1);
+        let response = |remaining: &str, reset: Option<&str>| {
+            let mut res = surf::http::Response::new(StatusCode::Ok);
+            res.insert_header(RATE_LIMIT_REMAINING_HEADER, remaining);
+            if let Some(reset) = reset {
+                res.insert_header(RATE_LIMIT_RESET_HEADER, reset);
+            }
+            Response::from(res)
+        };
+        assert_eq!(retry.rate_limit_pause(&response("2", Some("30"))), None);
+        assert_eq!(
+            retry.rate_limit_pause(&response("1", Some("30"))),
+            Some(Duration::from_secs(30))
+        );
+        assert!(retry.rate_limit_pause(&response("0", None)).unwrap() <= interval);
+        assert_eq!(
+            retry.rate_limit_pause(&response_with_header(RATE_LIMIT_REMAINING_HEADER, "0")),
+            None
+        );
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            rate_limit_reset_wait(1_700_000_045, now),
+            Duration::from_secs(45)
+        );
+        assert_eq!(rate_limit_reset_wait(1_600_000_000, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn rate_limit_pause_is_capped() {
+        let retry = RetryMiddleware::default().with_rate_limit_remaining_threshold(1);
+        let response = |reset: &str| {
+            let mut res = surf::http::Response::new(StatusCode::Ok);
+            res.insert_header(RATE_LIMIT_REMAINING_HEADER, "0");
+            res.insert_header(RATE_LIMIT_RESET_HEADER, reset);
+            Response::from(res)
+        };
+        assert_eq!(
+            retry.rate_limit_pause(&response("999999999")),
+            Some(Duration::from_secs(60))
+        );
+        let retry = retry.max_rate_limit_pause(Duration::from_secs(5));
+        assert_eq!(
+            retry.rate_limit_pause(&response("4102444800")),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            retry.rate_limit_pause(&response("2")),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn delay_multiplier_from_header_is_validated_and_capped() {
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::from_secs(1))
+            .with_delay_multiplier_from_header(true)
+            .max_delay_multiplier(5.0);
+        let wait = |value| {
+            let res = response_with_header(BACKOFF_MULTIPLIER_HEADER, value);
+            retry.retry_wait(&Ok(res), 1)
+        };
+        assert_eq!(wait("2.5"), Duration::from_millis(2500));
+        assert_eq!(wait("100"), Duration::from_secs(5));
+        assert_eq!(wait("-2"), Duration::from_secs(1));
+        assert_eq!(wait("0"), Duration::from_secs(1));
+        assert_eq!(wait("NaN"), Duration::from_secs(1));
+        assert_eq!(wait("fast"), Duration::from_secs(1));
+    }
+
+    /// Fails the first `failures` requests with an io error of the given kind.
+    #[derive(Debug)]
+    struct FailingConnection {
+        kind: std::io::ErrorKind,
+        message: &'static str,
+        failures: AtomicU32,
+    }
+
+    impl FailingConnection {
+        fn new(kind: std::io::ErrorKind, failures: u32) -> Self {
+            Self {
+                kind,
+                message: "connection failure",
+                failures: failures.into(),
+            }
+        }
+
+        fn with_message(mut self, message: &'static str) -> Self {
+            self.message = message;
+            self
+        }
+    }
+
+    #[surf::utils::async_trait]
+    impl Middleware for FailingConnection {
+        async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> Result<Response> {
+            let remaining = self.failures.load(Ordering::SeqCst);
+            if remaining > 0 {
+                self.failures.store(remaining - 1, Ordering::SeqCst);
+                return Err(std::io::Error::new(self.kind, self.message).into());
+            }
+            next.run(req, client).await
+        }
+    }
+
+    #[async_std::test]
+    async fn dns_errors_are_retried() -> Result<()> {
+        let mock_server = flaky_server(0, ResponseTemplate::new(429)).await;
+        let no_wait = TruncatedExponentialBackoff::new(Duration::ZERO, 1.0, Duration::ZERO);
+        let lookup_failure = || {
+            FailingConnection::new(std::io::ErrorKind::Other, 2)
+                .with_message("failed to lookup address information: Name or service not known")
+        };
+        let retry = RetryMiddleware::new(3, no_wait, 0).with_retry_on_dns_error(true);
+        let client = Client::new().with(retry).with(lookup_failure());
+        assert_eq!(client.send(get(&mock_server)).await?.status(), 200);
+
+        let client = Client::new()
+            .with(RetryMiddleware::new(3, no_wait, 0))
+            .with(lookup_failure());
+        assert!(client.send(get(&mock_server)).await.is_err());
+        let refused = FailingConnection::new(std::io::ErrorKind::ConnectionRefused, 1);
+        let retry = RetryMiddleware::new(3, no_wait, 0).with_retry_on_dns_error(true);
+        let client = Client::new().with(retry).with(refused);
+        assert!(client.send(get(&mock_server)).await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn goaway_cancellations_are_retried_immediately() -> Result<()> {
+        let mock_server = flaky_server(0, ResponseTemplate::new(429)).await;
+        let goaway = || {
+            FailingConnection::new(std::io::ErrorKind::Other, 2)
+                .with_message("HTTP/2 stream 1 was not closed cleanly: REFUSED_STREAM (err 7)")
+        };
+        let client = Client::new()
+            .with(RetryMiddleware::default())
+            .with(goaway());
+        let started = Instant::now();
+        assert_eq!(client.send(get(&mock_server)).await?.status(), 200);
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        let retry = RetryMiddleware::default().with_http2_goaway_retry(false);
+        let client = Client::new().with(retry).with(goaway());
+        assert!(client.send(get(&mock_server)).await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn pool_drain_wait_saturates() -> Result<()> {
+        let mock_server = flaky_server(0, ResponseTemplate::new(429)).await;
+        let no_wait = TruncatedExponentialBackoff::new(Duration::ZERO, 1.0, Duration::ZERO);
+        let waits = Arc::new(Mutex::new(Vec::new()));
+        let seen = waits.clone();
+        let retry = RetryMiddleware::new(1, no_wait, 0)
+            .with_custom_wait_computation(|_, _| Duration::ZERO)
+            .with_before_sleep_hook(move |wait| {
+                seen.lock().unwrap().push(wait);
+                Duration::ZERO
+            })
+            .with_connection_pool_drain_wait(Duration::MAX);
+        let client = Client::new().with(retry).with(FailingConnection::new(
+            std::io::ErrorKind::ConnectionReset,
+            1,
+        ));
+        assert_eq!(client.send(get(&mock_server)).await?.status(), 200);
+        assert_eq!(*waits.lock().unwrap(), vec![Duration::MAX]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn connection_reset_waits_for_pool_drain() -> Result<()> {
+        let mock_server = flaky_server(0, ResponseTemplate::new(429)).await;
+        let no_wait = TruncatedExponentialBackoff::new(Duration::ZERO, 1.0, Duration::ZERO);
+        let drain_timeout = Duration::from_millis(200);
+        let retry =
+            RetryMiddleware::new(3, no_wait, 0).with_connection_pool_drain_wait(drain_timeout);
+        let client = Client::new().with(retry).with(FailingConnection::new(
+            std::io::ErrorKind::ConnectionReset,
+            2,
+        ));
+        let started = Instant::now();
+        let res = client.send(get(&mock_server)).await?;
+        let elapsed = started.elapsed();
+        assert_eq!(res.status(), 200);
+        // the drain wait only applies to the first retry
+        assert!(elapsed >= drain_timeout, "{:?}", elapsed);
+        assert!(elapsed < drain_timeout * 2, "{:?}", elapsed);
+
+        // other errors are not retried
+        let retry =
+            RetryMiddleware::new(3, no_wait, 0).with_connection_pool_drain_wait(drain_timeout);
+        let client = Client::new().with(retry).with(FailingConnection::new(
+            std::io::ErrorKind::ConnectionRefused,
+            1,
+        ));
+        assert!(client.send(get(&mock_server)).await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn most_specific_endpoint_override_wins() -> Result<()> {
+        let no_wait = TruncatedExponentialBackoff::new(Duration::ZERO, 1.0, Duration::ZERO);
+        let retry = || {
+            RetryMiddleware::new(0, no_wait, 0)
+                .override_max_retries_for(Method::Get, "/critical", 2)
+                .override_max_retries_for(Method::Get, "/critical/less", 1)
+                .override_max_retries_for(Method::Post, "/", 5)
+        };
+        let send = |path: &'static str, failures| async move {
+            let mock_server = flaky_server(failures, ResponseTemplate::new(429)).await;
+            let url = format!("{}{}", &mock_server.uri(), path);
+            let req = Request::new(Method::Get, Url::parse(&url).unwrap());
+            Client::new()
+                .with(retry())
+                .send(req)
+                .await
+                .map(|res| res.status())
+        };
+        assert_eq!(send("/critical/more", 2).await?, 200);
+        assert_eq!(send("/critical/less", 2).await?, 429);
+        assert_eq!(send("/other", 1).await?, 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn consecutive_failures_adjust_policy_wait() -> Result<()> {
+        let interval = Duration::from_millis(100);
+        let retry = RetryMiddleware::new(
+            1,
+            TruncatedExponentialBackoff::new(interval, 1.0, interval),
+            0,
+        )
+        .with_policy_adjustment_on_consecutive_failures(2, 3.0);
+        assert!(retry.use_policy(1) <= interval);
+        retry.consecutive_failures.store(2, Ordering::Relaxed);
+        assert!(retry.use_policy(1) > interval * 2);
+        retry.consecutive_failures.store(0, Ordering::Relaxed);
+
+        let failures = retry.consecutive_failures.clone();
+        let client = Client::new().with(retry);
+        let mock_server = flaky_server(2, ResponseTemplate::new(429)).await;
+        let res = client.send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 429);
+        assert_eq!(failures.load(Ordering::Relaxed), 2);
+        let res = client.send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(failures.load(Ordering::Relaxed), 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn upgrade_requests_can_be_excluded_from_retries() -> Result<()> {
+        let retry = || {
+            RetryMiddleware::default()
+                .with_fast_first_retry(true)
+                .only_retry_codes(vec![StatusCode::ServiceUnavailable])
+        };
+        let upgrade = |mock_server: &MockServer| {
+            let mut req = get(mock_server);
+            req.insert_header(headers::CONNECTION, "keep-alive, Upgrade");
+            req.insert_header(headers::UPGRADE, "websocket");
+            req
+        };
+
+        let mock_server = flaky_server(1, ResponseTemplate::new(503)).await;
+        let client = Client::new().with(retry());
+        assert_eq!(client.send(upgrade(&mock_server)).await?.status(), 200);
+
+        let mock_server = flaky_server(1, ResponseTemplate::new(503)).await;
+        let client = Client::new().with(retry().with_retry_upgrade_requests(false));
+        assert_eq!(client.send(upgrade(&mock_server)).await?.status(), 503);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn before_sleep_hook_transforms_final_wait() -> Result<()> {
+        let mock_server = flaky_server(
+            1,
+            ResponseTemplate::new(429).insert_header("Retry-After", "30"),
+        )
+        .await;
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        let retry = RetryMiddleware::default().with_before_sleep_hook(move |wait| {
+            hook_seen.lock().unwrap().push(wait);
+            Duration::ZERO
+        });
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(*seen.lock().unwrap(), vec![Duration::from_secs(30)]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn no_sleep_on_last_retry_is_faster() -> Result<()> {
+        let interval = Duration::from_millis(100);
+        let exhaust = |skip_last| async move {
+            let mock_server = flaky_server(4, ResponseTemplate::new(429)).await;
+            let retry = RetryMiddleware::new(
+                3,
+                TruncatedExponentialBackoff::new(interval, 1.0, interval),
+                0,
+            )
+            .with_no_sleep_on_last_retry(skip_last);
+            let started = Instant::now();
+            let res = Client::new().with(retry).send(get(&mock_server)).await?;
+            assert_eq!(res.status(), 429);
+            Result::Ok(started.elapsed())
+        };
+        let with_sleep = exhaust(false).await?;
+        let without_sleep = exhaust(true).await?;
+        assert!(with_sleep >= interval * 3, "{:?}", with_sleep);
+        assert!(without_sleep < with_sleep, "{:?}", without_sleep);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn sticky_cookies_are_sent_with_retries() -> Result<()> {
+        let mock_server = flaky_server(
+            2,
+            ResponseTemplate::new(429)
+                .append_header("Set-Cookie", "JSESSIONID=abc; Path=/; HttpOnly")
+                .append_header("Set-Cookie", "theme=dark"),
+        )
+        .await;
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::ZERO)
+            .with_sticky_session_cookie(true);
+        let mut req = get(&mock_server);
+        req.insert_header(headers::COOKIE, "theme=light; lang=en");
+        let res = Client::new().with(retry).send(req).await?;
+        assert_eq!(res.status(), 200);
+        let cookies: Vec<String> = mock_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| r.headers[&headers::COOKIE].as_str().to_string())
+            .collect();
+        assert_eq!(
+            cookies,
+            vec![
+                "theme=light; lang=en",
+                "theme=dark; lang=en; JSESSIONID=abc",
+                "theme=dark; lang=en; JSESSIONID=abc",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn waits_are_rounded_up_to_quantization() {
+        let secs = Duration::from_secs;
+        let millis = Duration::from_millis;
+        for (wait, quantization, expected) in [
+            (secs(7), secs(5), secs(10)),
+            (secs(10), secs(5), secs(10)),
+            (Duration::ZERO, secs(5), Duration::ZERO),
+            (millis(1), secs(1), secs(1)),
+            (millis(1500), millis(400), millis(1600)),
+            (secs(3), Duration::ZERO, secs(3)),
+            (Duration::MAX, secs(7), Duration::MAX),
+        ] {
+            assert_eq!(
+                quantize(wait, quantization),
+                expected,
+                "{wait:?} / {quantization:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn cooling_period_keeps_previous_policy_wait() {
+        let policy = TruncatedExponentialBackoff::new(
+            Duration::from_millis(100),
+            2.0,
+            Duration::from_secs(10),
+        );
+        let cooling =
+            RetryMiddleware::new(3, policy, 0).with_policy_cooling_period(Duration::from_secs(60));
+        assert!(cooling.use_policy(1) <= Duration::from_millis(100));
+        assert!(cooling.use_policy(3) > Duration::from_millis(300));
+        assert!(cooling.use_policy(1) > Duration::from_millis(300));
+
+        let expired = RetryMiddleware::new(3, policy, 0).with_policy_cooling_period(Duration::ZERO);
+        assert!(expired.use_policy(3) > Duration::from_millis(300));
+        assert!(expired.use_policy(1) <= Duration::from_millis(100));
+    }
+
+    #[async_std::test]
+    async fn escalation_keeps_excluded_requests_unretried() -> Result<()> {
+        let mock_server = flaky_server(1, ResponseTemplate::new(429)).await;
+        let retry = RetryMiddleware::default()
+            .with_fast_first_retry(true)
+            .with_priority_escalation(|_| 5)
+            .with_mock_server_detection(true);
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 429);
+
+        let mock_server = flaky_server(1, ResponseTemplate::new(429)).await;
+        let retry = RetryMiddleware::default()
+            .with_fast_first_retry(true)
+            .with_priority_escalation(|_| 5)
+            .with_retry_upgrade_requests(false);
+        let mut req = get(&mock_server);
+        req.insert_header(headers::CONNECTION, "Upgrade");
+        req.insert_header(headers::UPGRADE, "websocket");
+        let res = Client::new().with(retry).send(req).await?;
+        assert_eq!(res.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn mock_server_detection_skips_retries() -> Result<()> {
+        let mock_server = flaky_server(1, ResponseTemplate::new(429)).await;
+        let retry = RetryMiddleware::default().with_mock_server_detection(true);
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 429);
+        Ok(())
+    }
+
+    #[test]
+    fn waits_are_aligned_to_rate_limit_windows() {
+        let secs = Duration::from_secs;
+        let minute = secs(60);
+        // 10:00:50 since the epoch, the next window starts in 10 seconds
+        let now = secs(600 + 50);
+        assert_eq!(align_to_window(now, secs(8), minute, secs(5)), secs(10));
+        assert_eq!(align_to_window(now, secs(10), minute, secs(5)), secs(10));
+        assert_eq!(align_to_window(now, secs(3), minute, secs(5)), secs(3));
+        assert_eq!(align_to_window(now, secs(12), minute, secs(5)), secs(12));
+        assert_eq!(align_to_window(now, secs(68), minute, secs(5)), secs(70));
+        assert_eq!(
+            align_to_window(now, secs(8), minute, Duration::ZERO),
+            secs(8)
+        );
+        assert_eq!(
+            align_to_window(now, secs(8), Duration::ZERO, secs(5)),
+            secs(8)
+        );
+    }
+
+    #[test]
+    fn attempt_timeouts_escalate_up_to_max() {
+        let secs = Duration::from_secs;
+        let retry = RetryMiddleware::default().with_per_attempt_timeout(secs(5));
+        assert_eq!(retry.attempt_timeout(3), Some(secs(5)));
+        let retry = retry.with_timeout_escalation(2.0, secs(30));
+        let timeouts: Vec<_> = (0..4)
+            .map(|attempt| retry.attempt_timeout(attempt))
+            .collect();
+        assert_eq!(
+            timeouts,
+            [
+                Some(secs(5)),
+                Some(secs(10)),
+                Some(secs(20)),
+                Some(secs(30))
+            ]
+        );
+        assert_eq!(RetryMiddleware::default().attempt_timeout(1), None);
+    }
+
+    #[async_std::test]
+    async fn timed_out_attempts_are_retried() -> Result<()> {
+        let mock_server = flaky_server(
+            1,
+            ResponseTemplate::new(200).set_delay(Duration::from_millis(500)),
+        )
+        .await;
+        let no_wait = TruncatedExponentialBackoff::new(Duration::ZERO, 1.0, Duration::ZERO);
+        let retry = RetryMiddleware::new(1, no_wait, 0)
+            .with_per_attempt_timeout(Duration::from_millis(100))
+            .with_timeout_escalation(2.0, Duration::from_secs(1));
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        let mock_server = flaky_server(
+            1,
+            ResponseTemplate::new(200).set_delay(Duration::from_millis(500)),
+        )
+        .await;
+        let retry = RetryMiddleware::new(0, no_wait, 0)
+            .with_per_attempt_timeout(Duration::from_millis(100));
+        let err = Client::new()
+            .with(retry)
+            .send(get(&mock_server))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::RequestTimeout);
+        Ok(())
+    }
+
+    #[test]
+    fn refreshed_policy_is_cached_for_refresh_interval() {
+        let refreshes = Arc::new(AtomicU32::new(0));
+        let refresh = |refreshes: Arc<AtomicU32>| {
+            move || -> Box<dyn RetryPolicy + Send + Sync> {
+                refreshes.fetch_add(1, Ordering::Relaxed);
+                let interval = Duration::from_millis(250);
+                Box::new(TruncatedExponentialBackoff::new(interval, 1.0, interval))
+            }
+        };
+        let retry = RetryMiddleware::default()
+            .with_policy_refresh_interval(Duration::from_secs(3600), refresh(refreshes.clone()));
+        for retries in 1..=3 {
+            let wait = retry.use_policy(retries);
+            assert!(wait <= Duration::from_millis(250) && wait > Duration::from_millis(200));
+        }
+        assert_eq!(refreshes.load(Ordering::Relaxed), 1);
+
+        let retry = RetryMiddleware::default()
+            .with_policy_refresh_interval(Duration::ZERO, refresh(refreshes.clone()));
+        retry.use_policy(1);
+        std::thread::sleep(Duration::from_millis(1));
+        retry.use_policy(1);
+        assert_eq!(refreshes.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn refreshed_policy_is_used_while_another_request_refreshes() {
+        let refresh = || -> Box<dyn RetryPolicy + Send + Sync> {
+            let interval = Duration::from_millis(250);
+            Box::new(TruncatedExponentialBackoff::new(interval, 1.0, interval))
+        };
+        let configured = Duration::from_millis(10);
+        let configured = TruncatedExponentialBackoff::new(configured, 1.0, configured);
+        let is_refreshed = |wait: Duration| wait > Duration::from_millis(200);
+        let retry = RetryMiddleware::new(3, configured, 0)
+            .with_policy_refresh_interval(Duration::ZERO, refresh);
+        assert!(is_refreshed(retry.use_policy(1)));
+
+        // another request refreshes the expired policy while a third one reads it
+        let refreshing = retry.policy_refreshing.clone();
+        let _refreshing = refreshing.lock().unwrap();
+        let _reading = retry.refreshed_policy.read().unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(is_refreshed(retry.use_policy(1)));
+    }
+
+    #[test]
+    fn waits_follow_local_queue_depth() {
+        let depth = Arc::new(AtomicU32::new(0));
+        let interval = Duration::from_millis(100);
+        let queue_depth = depth.clone();
+        let retry = RetryMiddleware::new(
+            3,
+            TruncatedExponentialBackoff::new(interval, 2.0, Duration::from_secs(10)),
+            0,
+        )
+        .with_delay_from_queue_depth(move || queue_depth.load(Ordering::Relaxed) as usize, 10)
+        .queue_depth_multiplier(3.0);
+        let wait = Duration::from_secs(1);
+        assert!(retry.queue_adjusted_wait(wait, true) <= interval);
+        assert_eq!(retry.queue_adjusted_wait(wait, false), wait);
+        depth.store(10, Ordering::Relaxed);
+        assert_eq!(retry.queue_adjusted_wait(wait, true), wait);
+        depth.store(11, Ordering::Relaxed);
+        assert_eq!(
+            retry.queue_adjusted_wait(wait, true),
+            Duration::from_secs(3)
+        );
+    }
+
+    #[async_std::test]
+    async fn empty_queue_keeps_retry_after() -> Result<()> {
+        let mock_server = flaky_server(
+            1,
+            ResponseTemplate::new(429).insert_header("Retry-After", "1"),
+        )
+        .await;
+        let interval = Duration::from_millis(10);
+        let retry = RetryMiddleware::new(
+            3,
+            TruncatedExponentialBackoff::new(interval, 2.0, Duration::from_secs(10)),
+            0,
+        )
+        .with_delay_from_queue_depth(|| 0, 10);
+        let started = Instant::now();
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        Ok(())
+    }
+
+    /// A streaming body sending `prefix` and then staying open.
+    fn open_stream(prefix: &'static str, mime: &str) -> Response {
+        #[derive(Debug)]
+        struct Open;
+
+        impl futures_lite::AsyncRead for Open {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                _: &mut std::task::Context<'_>,
+                _: &mut [u8],
+            ) -> std::task::Poll<std::io::Result<usize>> {
+                std::task::Poll::Pending
+            }
+        }
+
+        let mut res = surf::http::Response::new(StatusCode::ServiceUnavailable);
+        let mut body = Body::from_reader(
+            futures_lite::io::BufReader::new(Cursor::new(prefix).chain(Open)),
+            None,
+        );
+        body.set_mime(mime);
+        res.set_body(body);
+        res.into()
+    }
+
+    #[async_std::test]
+    async fn sse_reconnect_delay_does_not_wait_for_the_stream_to_end() -> Result<()> {
+        let retry = RetryMiddleware::default().with_sse_reconnect_delay(true);
+        let mut res = Ok(open_stream(
+            "retry: 10\ndata: update\n",
+            "text/event-stream",
+        ));
+        let delay =
+            async_std::future::timeout(Duration::from_secs(1), retry.sse_reconnect_delay(&mut res))
+                .await?;
+        assert_eq!(delay?, Some(Duration::from_millis(10)));
+
+        let mut res = Ok(open_stream("data: update\n\n", "text/event-stream"));
+        let delay =
+            async_std::future::timeout(Duration::from_secs(1), retry.sse_reconnect_delay(&mut res))
+                .await?;
+        assert_eq!(delay?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn sse_retry_or_event_end_completes_prefix() {
+        assert!(!ends_first_sse_retry_or_event(b"retry: 10"));
+        assert!(!ends_first_sse_retry_or_event(b"data: update\nretry: 1"));
+        assert!(ends_first_sse_retry_or_event(
+            b"data: update\nretry: 10\r\n"
+        ));
+        assert!(ends_first_sse_retry_or_event(b"data: update\n\n"));
+        assert!(ends_first_sse_retry_or_event(b"data: update\r\n\r\n"));
+    }
+
+    #[test]
+    fn sse_retry_field_is_parsed() {
+        let stream = "retry: 1000\n\nevent: update\ndata: retry: 7\nretry:2500\r\nretry: soon\n\n";
+        assert_eq!(parse_sse_retry(stream), Some(Duration::from_millis(2500)));
+        assert_eq!(parse_sse_retry("data: hello\n\n"), None);
+    }
+
+    #[async_std::test]
+    async fn sse_reconnect_delay_replaces_wait() -> Result<()> {
+        let mock_server = flaky_server(
+            1,
+            ResponseTemplate::new(503)
+                .insert_header("Retry-After", "60")
+                .set_body_raw("retry: 10\n\n", "text/event-stream"),
+        )
+        .await;
+        let retry = RetryMiddleware::default()
+            .only_retry_codes(vec![StatusCode::ServiceUnavailable])
+            .with_sse_reconnect_delay(true);
+        let started = Instant::now();
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[test]
+    fn server_timing_wait_is_parsed() {
+        let parse = |value: &str| {
+            parse_server_timing_wait(&headers::HeaderValue::from_bytes(value.into()).unwrap())
+        };
+        assert_eq!(parse("wait;dur=1500"), Some(Duration::from_millis(1500)));
+        assert_eq!(
+            parse("db;dur=53, wait;desc=\"Retry\";dur=2.5"),
+            Some(Duration::from_micros(2500))
+        );
+        assert_eq!(parse("db;dur=53"), None);
+        assert_eq!(parse("wait"), None);
+        assert_eq!(parse("wait;dur=-1"), None);
+    }
+
+    #[test]
+    fn older_requests_get_more_retries() {
+        let retry =
+            RetryMiddleware::default().with_linear_priority_escalation(Duration::from_secs(20));
+        assert_eq!(retry.escalated_max_retries(3, Duration::ZERO), 3);
+        assert_eq!(retry.escalated_max_retries(3, Duration::from_secs(19)), 3);
+        assert_eq!(retry.escalated_max_retries(3, Duration::from_secs(60)), 6);
+        assert_eq!(
+            RetryMiddleware::default().escalated_max_retries(3, Duration::from_secs(60)),
+            3
+        );
+        let retry = RetryMiddleware::default().with_linear_priority_escalation(Duration::ZERO);
+        assert_eq!(retry.escalated_max_retries(3, Duration::from_secs(60)), 3);
+    }
+
+    #[test]
+    fn first_retry_waits_initial_interval() {
+        let policy = TruncatedExponentialBackoff::new(
+            Duration::from_millis(100),
+            2.0,
+            Duration::from_secs(1),
+        );
+        let schedule = RetryMiddleware::new(3, policy, 0).backoff_schedule(3);
+        for (wait, expected) in schedule.iter().zip([100, 200, 400]) {
+            let expected = Duration::from_millis(expected);
+            assert!(*wait <= expected && *wait > expected - Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn aggressive_policy_makes_fast_retries_first() {
+        let slow =
+            TruncatedExponentialBackoff::new(Duration::from_secs(1), 2.0, Duration::from_secs(10));
+        let policy = AggressivePolicy::new(2, Duration::from_millis(10), slow);
+        let retry = RetryMiddleware::new(4, policy, 0);
+        let schedule = retry.backoff_schedule(4);
+        for (wait, expected) in schedule.iter().zip([10, 10, 1000, 2000]) {
+            let expected = Duration::from_millis(expected);
+            assert!(
+                *wait <= expected && *wait > expected.saturating_sub(Duration::from_millis(10)),
+                "{:?}",
+                schedule
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_schedule_simulates_waits() {
+        let policy = TruncatedExponentialBackoff::new(
+            Duration::from_millis(100),
+            2.0,
+            Duration::from_secs(1),
+        );
+        let retry = RetryMiddleware::new(5, policy, 0).with_fast_first_retry(true);
+        let schedule = retry.backoff_schedule(4);
+        assert_eq!(schedule.len(), 4);
+        assert_eq!(schedule[0], Duration::ZERO);
+        for (wait, expected) in schedule[1..].iter().zip([200, 400, 800]) {
+            let expected = Duration::from_millis(expected);
+            assert!(*wait <= expected && *wait > expected - Duration::from_millis(10));
+        }
+        assert_eq!(
+            retry.fmt_backoff_schedule(4),
+            concat!(
+                "attempt |         wait |        total\n",
+                "      1 |          0ns |          0ns\n",
+                "      2 |        200ms |        200ms\n",
+                "      3 |        400ms |        600ms\n",
+                "      4 |        800ms |         1.4s\n",
+            )
+        );
+    }
+
+    #[test]
+    fn sensitive_headers_are_redacted() {
+        let values =
+            HeaderValues::from(headers::HeaderValue::from_bytes(b"secret".to_vec()).unwrap());
+        let retry = RetryMiddleware::default()
+            .redact_header("X-Session")
+            .do_not_redact_header(headers::COOKIE);
+        for name in [
+            headers::AUTHORIZATION,
+            HeaderName::from("x-api-key"),
+            HeaderName::from("X-Session"),
+        ] {
+            assert_eq!(
+                retry.fmt_header(&name, &values),
+                format!("{}=[REDACTED]", name)
+            );
+        }
+        for name in [headers::COOKIE, headers::ACCEPT] {
+            assert_eq!(
+                retry.fmt_header(&name, &values),
+                format!("{}={}", name, values)
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn response_validator_retries_pending_responses() -> Result<()> {
+        let mock_server = flaky_server(
+            2,
+            ResponseTemplate::new(200).insert_header("X-Operation-Status", "pending"),
+        )
+        .await;
+        let retry = RetryMiddleware::new(
+            2,
+            ExponentialBackoff::builder().build_with_max_retries(2),
+            0,
+        )
+        .with_custom_wait_computation(|_, _| Duration::ZERO)
+        .with_response_validator(|res| res.header("X-Operation-Status").is_none());
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        assert!(res.header("X-Operation-Status").is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn server_attempt_budget_limits_retries() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header(MAX_CLIENT_RETRIES_HEADER, "1"))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::ZERO)
+            .with_attempt_budget_from_server(true);
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn retries_are_signed_again() -> Result<()> {
+        let mock_server = flaky_server(1, ResponseTemplate::new(429)).await;
+        let retry = RetryMiddleware::new(
+            1,
+            ExponentialBackoff::builder().build_with_max_retries(1),
+            0,
+        )
+        .with_custom_wait_computation(|_, _| Duration::ZERO)
+        .with_request_signing_on_retry(|req, now| {
+            req.set_header("X-Timestamp", now.timestamp().to_string());
+        });
+        let mut req = get(&mock_server);
+        req.set_header("X-Timestamp", "0");
+        Client::new().with(retry).send(req).await?;
+        let received = mock_server.received_requests().await.unwrap();
+        let timestamps: Vec<&str> = received
+            .iter()
+            .map(|r| r.headers[&HeaderName::from("X-Timestamp")].as_str())
+            .collect();
+        assert_eq!(timestamps[0], "0");
+        assert!(timestamps[1].parse::<i64>().unwrap() > 0);
+        Ok(())
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingBreaker {
+        failures: AtomicU32,
+        successes: AtomicU32,
+    }
+
+    impl CircuitBreakerGate for CountingBreaker {
+        fn is_open(&self) -> bool {
+            self.failures.load(Ordering::Relaxed) >= 2
+        }
+
+        fn record_failure(&self) {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn record_success(&self) {
+            self.successes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[async_std::test]
+    async fn open_circuit_breaker_stops_retries() -> Result<()> {
+        let mock_server = flaky_server(2, ResponseTemplate::new(429)).await;
+        let breaker = Arc::new(CountingBreaker::default());
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::ZERO)
+            .with_circuit_breaker_integration(breaker.clone());
+        let client = Client::new().with(retry);
+        let res = client.send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 429);
+        assert_eq!(breaker.failures.load(Ordering::Relaxed), 2);
+        let res = client.send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(breaker.successes.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn backoff_stats_record_waits() -> Result<()> {
+        let mock_server = flaky_server(2, ResponseTemplate::new(429)).await;
+        let stats = BackoffStatsRecorder::default();
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, retries| Duration::from_millis(10 * retries as u64))
+            .with_exponential_backoff_stats(stats.clone());
+        assert_eq!(retry.backoff_stats(), None);
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        let observed = stats.get().unwrap();
+        assert_eq!(observed.count, 2);
+        assert_eq!(observed.min_observed, Duration::from_millis(10));
+        assert_eq!(observed.max_observed, Duration::from_millis(20));
+        assert!((observed.mean_observed_ms - 15.0).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn cancellation_aborts_retries() -> Result<()> {
+        let mock_server = flaky_server(1, ResponseTemplate::new(429)).await;
+        let token = Arc::new(AtomicBool::new(false));
+        let cancel = token.clone();
+        let retry = RetryMiddleware::default()
+            .with_cancellation_token(token)
+            .with_before_sleep_hook(move |_| {
+                cancel.store(true, Ordering::Relaxed);
+                Duration::ZERO
+            });
+        let err = Client::new()
+            .with(retry)
+            .send(get(&mock_server))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "retry cancelled");
+        assert_eq!(err.status(), StatusCode::BadRequest);
+        assert!(err.downcast_ref::<RetryCancelled>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn cancellation_interrupts_the_wait() -> Result<()> {
+        let mock_server = flaky_server(1, ResponseTemplate::new(429)).await;
+        let token = Arc::new(AtomicBool::new(false));
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::from_secs(60))
+            .with_cancellation_token(token.clone());
+        let cancel = async_std::task::spawn(async move {
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            token.store(true, Ordering::Relaxed);
+        });
+        let started = Instant::now();
+        let err = Client::new()
+            .with(retry)
+            .send(get(&mock_server))
+            .await
+            .unwrap_err();
+        cancel.await;
+        assert!(err.downcast_ref::<RetryCancelled>().is_some());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn every_attempt_gets_a_new_request_id() -> Result<()> {
+        let mock_server = flaky_server(2, ResponseTemplate::new(429)).await;
+        let ids = AtomicU32::new(0);
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::ZERO)
+            .with_per_retry_request_id("X-Attempt-Id", move || {
+                format!("attempt-{}", ids.fetch_add(1, Ordering::Relaxed))
+            });
+        let mut req = get(&mock_server);
+        req.set_header("X-Session-Id", "session");
+        Client::new().with(retry).send(req).await?;
+        let received = mock_server.received_requests().await.unwrap();
+        let header = |r: &wiremock::Request, name: &str| {
+            r.headers[&HeaderName::from(name)].as_str().to_string()
+        };
+        let attempt_ids: Vec<String> = received.iter().map(|r| header(r, "X-Attempt-Id")).collect();
+        assert_eq!(attempt_ids, ["attempt-0", "attempt-1", "attempt-2"]);
+        assert!(received
+            .iter()
+            .all(|r| header(r, "X-Session-Id") == "session"));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn failed_health_check_skips_retry() -> Result<()> {
+        let mock_server = flaky_server(1, ResponseTemplate::new(429)).await;
+        let checks = Arc::new(AtomicU32::new(0));
+        let health_checks = checks.clone();
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::ZERO)
+            .with_host_health_check(move |_| {
+                let healthy = health_checks.fetch_add(1, Ordering::Relaxed) > 0;
+                Box::pin(async move { healthy })
+            });
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(checks.load(Ordering::Relaxed), 2);
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn health_check_requests_are_not_retried() -> Result<()> {
+        use wiremock::matchers::path;
+
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/health"))
+            .respond_with(ResponseTemplate::new(429))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(path("/"))
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(path("/"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let health = format!("{}/health", mock_server.uri());
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::ZERO)
+            .with_host_health_check(move |client| {
+                let health = health.clone();
+                Box::pin(async move {
+                    let res = client.get(health).await;
+                    res.map_or(false, |res| res.status() == StatusCode::TooManyRequests)
+                })
+            });
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn no_retries_outside_retry_window() -> Result<()> {
+        let mock_server = flaky_server(1, ResponseTemplate::new(429)).await;
+        let retry = RetryMiddleware::default().with_retry_window(|_| false);
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn response_headers_are_extracted_into_retry_context() -> Result<()> {
+        let mock_server = flaky_server(
+            2,
+            ResponseTemplate::new(429)
+                .insert_header("Retry-After", "5")
+                .insert_header("X-Rate-Limit-Window", "60"),
+        )
+        .await;
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::ZERO)
+            .extract_response_header::<u64>("X-Rate-Limit-Window")
+            .extract_response_header::<String>("X-Rate-Limit-Window")
+            .extract_retry_after();
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        let context = res.ext::<RetryContext>().unwrap();
+        assert_eq!(context.retries(), 2);
+        assert_eq!(context.get::<u64>("X-Rate-Limit-Window"), Some(&60));
+        assert_eq!(
+            context
+                .get::<String>("X-Rate-Limit-Window")
+                .map(String::as_str),
+            Some("60")
+        );
+        assert_eq!(context.get::<u32>("X-Rate-Limit-Window"), None);
+        assert_eq!(
+            context.get::<Duration>(headers::RETRY_AFTER),
+            Some(&Duration::from_secs(5))
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[async_std::test]
+    async fn serde_policy_update_applies_to_next_retry() -> Result<()> {
+        let policy =
+            SerdePolicy::new(r#"{"initial_ms": 100, "multiplier": 1.0, "max_interval_ms": 100}"#)
+                .unwrap();
+        let waits = Arc::new(Mutex::new(Vec::new()));
+        let (hook_policy, hook_waits) = (policy.clone(), waits.clone());
+        let retry =
+            RetryMiddleware::with_serde_policy(2, policy, 0).with_before_sleep_hook(move |wait| {
+                hook_waits.lock().unwrap().push(wait);
+                hook_policy
+                    .update(r#"{"initial_ms": 1000, "multiplier": 1.0, "max_interval_ms": 1000}"#)
+                    .unwrap();
+                Duration::ZERO
+            });
+        let mock_server = flaky_server(2, ResponseTemplate::new(429)).await;
+        let res = Client::new().with(retry).send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        let waits = waits.lock().unwrap();
+        assert!(waits[0] <= Duration::from_millis(100));
+        assert!(waits[1] > Duration::from_millis(500));
+        Ok(())
+    }
+
+    #[cfg(feature = "stale-cache")]
+    #[async_std::test]
+    async fn stale_response_is_served_once_retries_are_exhausted() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        Mock::given(wiremock::matchers::any())
+            .respond_with(ResponseTemplate::new(429))
+            .mount(&mock_server)
+            .await;
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::ZERO)
+            .with_response_cache_on_exhaustion(|_| {
+                Some(surf::http::Response::new(StatusCode::Ok).into())
+            });
+        let client = Client::new().with(retry);
+        let res = client.send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.header(STALE_HEADER).unwrap().as_str(), "true");
+        let url = Url::parse(&mock_server.uri()).unwrap();
+        let res = client.send(Request::new(Method::Post, url)).await?;
+        assert_eq!(res.status(), 429);
+        assert!(res.header(STALE_HEADER).is_none());
+        Ok(())
+    }
+
+    #[cfg(feature = "stale-cache")]
+    #[async_std::test]
+    async fn stale_response_replaces_errors() -> Result<()> {
+        let mock_server = flaky_server(0, ResponseTemplate::new(429)).await;
+        let retry = RetryMiddleware::default().with_response_cache_on_exhaustion(|_| {
+            Some(surf::http::Response::new(StatusCode::Ok).into())
+        });
+        let client = Client::new().with(retry).with(FailingConnection::new(
+            std::io::ErrorKind::ConnectionRefused,
+            1,
+        ));
+        let res = client.send(get(&mock_server)).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.header(STALE_HEADER).unwrap().as_str(), "true");
+        Ok(())
+    }
+
+    #[cfg(feature = "deduplication")]
+    #[async_std::test]
+    async fn concurrent_identical_retries_are_deduplicated() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("Hello!")
+                    .set_delay(Duration::from_millis(200)),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::from_millis(50))
+            .with_response_deduplication(true);
+        let client = Client::new().with(retry);
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let client = client.clone();
+                let req = get(&mock_server);
+                async_std::task::spawn(async move { client.recv_string(req).await })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await?, "Hello!");
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "deduplication")]
+    #[async_std::test]
+    async fn retries_with_different_credentials_are_not_deduplicated() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(2)
+            .mount(&mock_server)
+            .await;
+        for user in ["alice", "bob"] {
+            Mock::given(method("GET"))
+                .and(wiremock::matchers::header("Authorization", user))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_string(user)
+                        .set_delay(Duration::from_millis(200)),
+                )
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+        }
+        let retry = RetryMiddleware::default()
+            .with_custom_wait_computation(|_, _| Duration::from_millis(50))
+            .with_response_deduplication(true);
+        let client = Client::new().with(retry);
+        let tasks: Vec<_> = ["alice", "bob"]
+            .into_iter()
+            .map(|user| {
+                let client = client.clone();
+                let mut req = get(&mock_server);
+                req.insert_header("Authorization", user);
+                async_std::task::spawn(async move { (user, client.recv_string(req).await) })
+            })
+            .collect();
+        for task in tasks {
+            let (user, body) = task.await;
+            assert_eq!(body?, user);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "typemap")]
+    #[test]
+    fn type_map_value_round_trip() {
+        let value = RetryMiddleware::default().into_type_map_value();
+        let retry = RetryMiddleware::<ExponentialBackoff>::from_type_map_value(&value).unwrap();
+        assert_eq!(retry.max_retries, 3);
+        assert!(
+            RetryMiddleware::<TruncatedExponentialBackoff>::from_type_map_value(&value).is_none()
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn retry_after_date_uses_local_clock() {
+        use chrono::{TimeZone, Utc};
+
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap());
+        let retry = RetryMiddleware::default().with_local_clock(clock.clone());
+        let retry_after =
+            headers::HeaderValue::from_bytes(b"Wed, 21 Oct 2015 07:30:00 GMT".to_vec()).unwrap();
+        let secs = retry_to_seconds(&retry_after, retry.clock.system_now()).unwrap();
+        assert_eq!(secs, 120);
+        clock.advance(chrono::Duration::seconds(90));
+        let secs = retry_to_seconds(&retry_after, retry.clock.system_now()).unwrap();
+        assert_eq!(secs, 30);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn waits_use_local_clock_only_for_retry_after() {
+        use chrono::{TimeZone, Utc};
+
+        let policy = TruncatedExponentialBackoff::new(
+            Duration::from_millis(100),
+            2.0,
+            Duration::from_secs(1),
+        );
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap());
+        let retry = RetryMiddleware::new(3, policy, 7).with_local_clock(clock);
+        for (wait, expected) in retry.backoff_schedule(2).iter().zip([100, 200]) {
+            let expected = Duration::from_millis(expected);
+            assert!(*wait <= expected && *wait > expected - Duration::from_millis(10));
+        }
+
+        let mut res = surf::http::Response::new(StatusCode::TooManyRequests);
+        res.insert_header(headers::RETRY_AFTER, "Wed, 21 Oct 2015 07:30:00 GMT");
+        let res = Ok(Response::from(res));
+        assert_eq!(retry.retry_wait(&res, 1), Duration::from_secs(120));
     }
 }
@@ -0,0 +1,133 @@
+use chrono::Utc;
+use retry_policies::{RetryDecision, RetryPolicy};
+use std::time::Duration;
+
+/// Exponential backoff whose interval stops growing once it reaches `max_interval`.
+///
+/// The wait for a retry is `initial * multiplier ^ n_past_retries`, capped at `max_interval`. Once the cap is
+/// reached retries continue at that constant rate until the middleware's `max_retries` is exhausted. This is the
+/// base algorithm of the "full jitter" approach described in
+/// [Exponential Backoff And Jitter](https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/),
+/// without the jitter component.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TruncatedExponentialBackoff {
+    /// Wait before the first retry.
+    pub initial: Duration,
+    /// Factor the wait is multiplied by on each subsequent retry.
+    pub multiplier: f64,
+    /// Upper bound for the wait between two retries.
+    pub max_interval: Duration,
+}
+
+impl TruncatedExponentialBackoff {
+    /// Construct the policy with provided options.
+    pub fn new(initial: Duration, multiplier: f64, max_interval: Duration) -> Self {
+        Self {
+            initial,
+            multiplier,
+            max_interval,
+        }
+    }
+
+    /// The wait for a retry given the number of retries made so far.
+    pub fn interval(&self, n_past_retries: u32) -> Duration {
+        let exponent = i32::try_from(n_past_retries).unwrap_or(i32::MAX);
+        let secs = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        if !secs.is_finite() || secs >= self.max_interval.as_secs_f64() {
+            self.max_interval
+        } else {
+            Duration::from_secs_f64(secs.max(0.0))
+        }
+    }
+}
+
+impl RetryPolicy for TruncatedExponentialBackoff {
+    fn should_retry(&self, n_past_retries: u32) -> RetryDecision {
+        match chrono::Duration::from_std(self.interval(n_past_retries)) {
+            Ok(wait) => RetryDecision::Retry {
+                execute_after: Utc::now() + wait,
+            },
+            Err(_) => RetryDecision::DoNotRetry,
+        }
+    }
+}
+
+/// Two tier policy retrying quickly at first and then backing off with another policy.
+///
+/// The first `fast_retries` retries wait `fast_delay`, subsequent retries are delegated to `then` as if they were
+/// its first retries. Some failures are transient blips a fast retry recovers from, while a failure surviving the
+/// fast retries is likely caused by an overload and needs the full backoff.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AggressivePolicy<T> {
+    /// Number of retries using the fast delay.
+    pub fast_retries: u32,
+    /// Wait before each of the fast retries.
+    pub fast_delay: Duration,
+    /// Policy for the retries after the fast ones.
+    pub then: T,
+}
+
+impl<T: RetryPolicy> AggressivePolicy<T> {
+    /// Construct the policy with provided options.
+    pub fn new(fast_retries: u32, fast_delay: Duration, then: T) -> Self {
+        Self {
+            fast_retries,
+            fast_delay,
+            then,
+        }
+    }
+}
+
+impl<T: RetryPolicy> RetryPolicy for AggressivePolicy<T> {
+    fn should_retry(&self, n_past_retries: u32) -> RetryDecision {
+        if n_past_retries >= self.fast_retries {
+            return self.then.should_retry(n_past_retries - self.fast_retries);
+        }
+        match chrono::Duration::from_std(self.fast_delay) {
+            Ok(wait) => RetryDecision::Retry {
+                execute_after: Utc::now() + wait,
+            },
+            Err(_) => RetryDecision::DoNotRetry,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait(decision: RetryDecision) -> Duration {
+        match decision {
+            RetryDecision::Retry { execute_after } => {
+                (execute_after - Utc::now()).to_std().unwrap_or_default()
+            }
+            RetryDecision::DoNotRetry => panic!("expected a retry"),
+        }
+    }
+
+    #[test]
+    fn aggressive_policy_switches_to_slow_backoff() {
+        let slow =
+            TruncatedExponentialBackoff::new(Duration::from_secs(1), 2.0, Duration::from_secs(10));
+        let policy = AggressivePolicy::new(2, Duration::from_millis(10), slow);
+        for n_past_retries in 0..2 {
+            assert!(wait(policy.should_retry(n_past_retries)) <= Duration::from_millis(10));
+        }
+        for (n_past_retries, slow_wait) in [(2, 1), (3, 2), (4, 4)] {
+            let wait = wait(policy.should_retry(n_past_retries));
+            let slow_wait = Duration::from_secs(slow_wait);
+            assert!(wait <= slow_wait && wait > slow_wait - Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn interval_is_truncated_at_max_interval() {
+        let policy =
+            TruncatedExponentialBackoff::new(Duration::from_secs(1), 2.0, Duration::from_secs(5));
+        assert_eq!(policy.interval(0), Duration::from_secs(1));
+        assert_eq!(policy.interval(1), Duration::from_secs(2));
+        assert_eq!(policy.interval(2), Duration::from_secs(4));
+        assert_eq!(policy.interval(3), Duration::from_secs(5));
+        assert_eq!(policy.interval(u32::MAX), Duration::from_secs(5));
+    }
+}
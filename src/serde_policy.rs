@@ -0,0 +1,91 @@
+use crate::TruncatedExponentialBackoff;
+use retry_policies::{RetryDecision, RetryPolicy};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// Retry configuration deserialized by a [`SerdePolicy`], evaluated like a [`TruncatedExponentialBackoff`].
+///
+/// ```json
+/// { "initial_ms": 500, "multiplier": 2.0, "max_interval_ms": 30000 }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// Wait before the first retry in milliseconds.
+    pub initial_ms: u64,
+    /// Factor the wait is multiplied by on each subsequent retry. _Default 2_.
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    /// Upper bound for the wait between two retries in milliseconds.
+    pub max_interval_ms: u64,
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+impl From<RetryConfig> for TruncatedExponentialBackoff {
+    fn from(config: RetryConfig) -> Self {
+        TruncatedExponentialBackoff::new(
+            Duration::from_millis(config.initial_ms),
+            config.multiplier,
+            Duration::from_millis(config.max_interval_ms),
+        )
+    }
+}
+
+/// Retry policy configured from a JSON encoded [`RetryConfig`] that can be replaced at runtime.
+///
+/// Clones share the configuration, keep one to [`SerdePolicy::update`] the policy used by the middleware.
+/// The number of retries is still limited by the middleware's `max_retries`.
+#[derive(Debug, Clone)]
+pub struct SerdePolicy {
+    config: Arc<RwLock<TruncatedExponentialBackoff>>,
+}
+
+impl SerdePolicy {
+    /// Construct the policy from a JSON encoded [`RetryConfig`].
+    pub fn new(json: &str) -> serde_json::Result<Self> {
+        let config: RetryConfig = serde_json::from_str(json)?;
+        Ok(Self {
+            config: Arc::new(RwLock::new(config.into())),
+        })
+    }
+
+    /// Replace the configuration, taking effect from the next retry on.
+    ///
+    /// The current configuration is kept if `json` is not a valid [`RetryConfig`].
+    pub fn update(&self, json: &str) -> serde_json::Result<()> {
+        let config: RetryConfig = serde_json::from_str(json)?;
+        *self.config.write().unwrap() = config.into();
+        Ok(())
+    }
+}
+
+impl RetryPolicy for SerdePolicy {
+    fn should_retry(&self, n_past_retries: u32) -> RetryDecision {
+        self.config.read().unwrap().should_retry(n_past_retries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_update_keeps_configuration() {
+        let policy = SerdePolicy::new(r#"{"initial_ms": 100, "max_interval_ms": 1000}"#).unwrap();
+        assert!(policy.update(r#"{"initial_ms": "fast"}"#).is_err());
+        let config = *policy.config.read().unwrap();
+        assert_eq!(config.interval(1), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn first_retry_waits_initial_ms() {
+        let policy = SerdePolicy::new(r#"{"initial_ms": 100, "max_interval_ms": 1000}"#).unwrap();
+        let wait = crate::RetryMiddleware::with_serde_policy(3, policy, 0).backoff_schedule(1)[0];
+        assert!(wait <= Duration::from_millis(100) && wait > Duration::from_millis(90));
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Statistics of the waits before retries, see
+/// [`RetryMiddleware::with_exponential_backoff_stats`](crate::RetryMiddleware::with_exponential_backoff_stats).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExponentialBackoffStats {
+    /// Shortest wait before a retry.
+    pub min_observed: Duration,
+    /// Longest wait before a retry.
+    pub max_observed: Duration,
+    /// Mean wait before a retry in milliseconds.
+    pub mean_observed_ms: f64,
+    /// Number of waits observed.
+    pub count: u64,
+}
+
+impl ExponentialBackoffStats {
+    fn new(wait: Duration) -> Self {
+        Self {
+            min_observed: wait,
+            max_observed: wait,
+            mean_observed_ms: wait.as_secs_f64() * 1000.0,
+            count: 1,
+        }
+    }
+
+    fn record(&mut self, wait: Duration) {
+        self.min_observed = self.min_observed.min(wait);
+        self.max_observed = self.max_observed.max(wait);
+        self.count += 1;
+        let wait_ms = wait.as_secs_f64() * 1000.0;
+        self.mean_observed_ms += (wait_ms - self.mean_observed_ms) / self.count as f64;
+    }
+}
+
+/// Collects [`ExponentialBackoffStats`] across all requests of the middleware it is passed to.
+///
+/// Clones share the same statistics, keep one to read or reset them after adding the middleware to a client.
+///
+/// # Example
+/// ```
+/// use surf_retry::{BackoffStatsRecorder, RetryMiddleware};
+///
+/// let stats = BackoffStatsRecorder::default();
+/// let retry = RetryMiddleware::default().with_exponential_backoff_stats(stats.clone());
+/// let client = surf::Client::new().with(retry);
+/// assert_eq!(stats.get(), None);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct BackoffStatsRecorder(Arc<Mutex<Option<ExponentialBackoffStats>>>);
+
+impl BackoffStatsRecorder {
+    /// The statistics of the waits so far, `None` until the first retry.
+    pub fn get(&self) -> Option<ExponentialBackoffStats> {
+        *self.0.lock().unwrap()
+    }
+
+    /// Discard the statistics collected so far.
+    pub fn reset(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    pub(crate) fn record(&self, wait: Duration) {
+        let mut stats = self.0.lock().unwrap();
+        match stats.as_mut() {
+            Some(stats) => stats.record(wait),
+            None => *stats = Some(ExponentialBackoffStats::new(wait)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_are_aggregated_until_reset() {
+        let stats = BackoffStatsRecorder::default();
+        for wait in [100, 400, 250] {
+            stats.clone().record(Duration::from_millis(wait));
+        }
+        assert_eq!(
+            stats.get(),
+            Some(ExponentialBackoffStats {
+                min_observed: Duration::from_millis(100),
+                max_observed: Duration::from_millis(400),
+                mean_observed_ms: 250.0,
+                count: 3,
+            })
+        );
+        stats.reset();
+        assert_eq!(stats.get(), None);
+    }
+}
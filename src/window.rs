@@ -0,0 +1,63 @@
+use chrono::{DateTime, Datelike, Timelike, Utc, Weekday};
+
+/// Daily time window in UTC for [`RetryMiddleware::with_retry_window`](crate::RetryMiddleware::with_retry_window).
+///
+/// # Example
+/// ```
+/// use surf_retry::{RetryMiddleware, RetryWindow};
+///
+/// let window = RetryWindow::business_hours_utc();
+/// let retry = RetryMiddleware::default().with_retry_window(move |now| window.contains(now));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryWindow {
+    /// First hour of the window, inclusive.
+    pub start_hour: u32,
+    /// Hour the window ends at, exclusive.
+    pub end_hour: u32,
+    /// Whether the window is closed on Saturdays and Sundays.
+    pub weekdays_only: bool,
+}
+
+impl RetryWindow {
+    /// Construct the window with provided options.
+    pub fn new(start_hour: u32, end_hour: u32, weekdays_only: bool) -> Self {
+        Self {
+            start_hour,
+            end_hour,
+            weekdays_only,
+        }
+    }
+
+    /// 9am to 5pm UTC, Monday to Friday.
+    pub fn business_hours_utc() -> Self {
+        Self::new(9, 17, true)
+    }
+
+    /// Whether `time` falls into the window.
+    pub fn contains(&self, time: DateTime<Utc>) -> bool {
+        if self.weekdays_only && matches!(time.weekday(), Weekday::Sat | Weekday::Sun) {
+            return false;
+        }
+        (self.start_hour..self.end_hour).contains(&time.hour())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn business_hours_cover_weekday_office_hours() {
+        let window = RetryWindow::business_hours_utc();
+        // 2023-01-02 is a Monday
+        let at = |day, hour, min| Utc.with_ymd_and_hms(2023, 1, day, hour, min, 0).unwrap();
+        assert!(window.contains(at(2, 9, 0)));
+        assert!(window.contains(at(6, 16, 59)));
+        assert!(!window.contains(at(2, 8, 59)));
+        assert!(!window.contains(at(2, 17, 0)));
+        assert!(!window.contains(at(7, 12, 0)));
+        assert!(!window.contains(at(8, 12, 0)));
+    }
+}